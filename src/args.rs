@@ -1,21 +1,40 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::saucenao::SauceIndex;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
 pub struct Args {
     #[clap(subcommand)]
     pub subcommand: Command,
+
+    /// Runs all lookups but only prints the changes instead of sending them to hydrus.
+    /// The changes are printed to stdout while logs are written to stderr
+    #[clap(long, global = true)]
+    pub dry_run: bool,
+
+    /// The format the changes are printed in when running with --dry-run
+    #[clap(long, global = true, value_enum, default_value = "text")]
+    pub dry_run_format: DryRunFormat,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum DryRunFormat {
+    Text,
+    Json,
 }
 
 #[derive(Subcommand, Clone, Debug)]
 pub enum Command {
-    /// Looks up files on saucenao and sends urls to hydrus to be imported
+    /// Looks up files on saucenao and sends the url of the best match of each site to hydrus to be imported
     #[clap(name = "send-url")]
     FindAndSendUrl(LookupOptions),
 
-    /// Looks up files on saucenao and maps the tags found on pixiv to the files
+    /// Looks up files on saucenao and maps the tags found on pixiv and boorus to the files
     #[clap(name = "send-tags")]
     FindAndSendTags(LookupOptions),
 
@@ -27,9 +46,13 @@ pub enum Command {
     #[clap(name = "import-fedi-posts")]
     ImportFediPosts(ImportUrlsOptions),
 
+    /// Imports the media of all posts of a subreddit, user, multireddit or saved posts listing
+    #[clap(name = "import-reddit-listing")]
+    ImportRedditListing(ImportRedditListingOptions),
+
     /// Looks up a list of urls and imports media found for them
     #[clap(name = "import-urls")]
-    ImportUrls(ImportUrlsOptions),
+    ImportUrls(ImportMixedUrlsOptions),
 
     /// Tag a file with a given identifier. The identifier is sent via stdin
     #[clap(name = "tag")]
@@ -46,9 +69,185 @@ pub struct LookupOptions {
     #[clap(long)]
     pub finish_tag: Option<String>,
 
+    /// Tag that is assigned to files without any sauce
+    #[clap(long)]
+    pub no_match_tag: Option<String>,
+
+    /// Tag that is assigned to files that only have sauces with a low similarity
+    #[clap(long)]
+    pub low_similarity_tag: Option<String>,
+
+    /// Tag that is assigned to files whose sauces don't agree on a single source
+    #[clap(long)]
+    pub ambiguous_tag: Option<String>,
+
+    /// Tag that is assigned to files that failed to be processed
+    #[clap(long)]
+    pub error_tag: Option<String>,
+
     /// Tags used to search for files
     #[clap(short, long)]
     pub tags: Vec<String>,
+
+    #[clap(flatten)]
+    pub search: SearchOptions,
+
+    #[clap(flatten)]
+    pub matching: MatchOptions,
+
+    #[clap(flatten)]
+    pub pages: PageOptions,
+
+    /// Ignores cached lookup results and looks up all files again
+    #[clap(long)]
+    pub refresh: bool,
+
+    /// Resumes the previous run with the same options by skipping
+    /// all files that have already been processed successfully
+    #[clap(long)]
+    pub resume: bool,
+}
+
+/// System predicates used to narrow down the file search.
+/// Defaults can be set in the `[search]` section of the config
+#[derive(clap::Args, Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchOptions {
+    /// Only searches for files in the inbox. `--inbox=false` overrides the config
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub inbox: Option<bool>,
+
+    /// Only searches for archived files. `--archive=false` overrides the config
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub archive: Option<bool>,
+
+    /// Only searches for files with the given types (e.g. `image/png` or `image`)
+    #[clap(long)]
+    pub filetype: Vec<String>,
+
+    /// Only searches for files larger than the given size (e.g. `500KB`)
+    #[clap(long)]
+    pub min_size: Option<String>,
+
+    /// Only searches for files smaller than the given size (e.g. `10MB`)
+    #[clap(long)]
+    pub max_size: Option<String>,
+
+    /// Only searches for files imported within the given time (e.g. `12h`, `1d` or `2w`)
+    #[clap(long)]
+    pub imported_within: Option<String>,
+
+    /// The maximum number of files to process
+    #[clap(long)]
+    pub limit: Option<u64>,
+
+    /// The order the files are processed in
+    #[clap(long, value_enum)]
+    pub sort: Option<SortBy>,
+
+    /// Sorts the files in ascending instead of descending order.
+    /// `--ascending=false` overrides the config
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub ascending: Option<bool>,
+
+    /// The file service to search in
+    #[clap(long)]
+    pub file_service: Option<String>,
+}
+
+impl SearchOptions {
+    /// Fills all options that haven't been set with the given defaults
+    pub fn or(self, defaults: SearchOptions) -> Self {
+        Self {
+            inbox: self.inbox.or(defaults.inbox),
+            archive: self.archive.or(defaults.archive),
+            filetype: if self.filetype.is_empty() {
+                defaults.filetype
+            } else {
+                self.filetype
+            },
+            min_size: self.min_size.or(defaults.min_size),
+            max_size: self.max_size.or(defaults.max_size),
+            imported_within: self.imported_within.or(defaults.imported_within),
+            limit: self.limit.or(defaults.limit),
+            sort: self.sort.or(defaults.sort),
+            ascending: self.ascending.or(defaults.ascending),
+            file_service: self.file_service.or(defaults.file_service),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortBy {
+    FileSize,
+    Duration,
+    ImportTime,
+    FileType,
+    Random,
+    Width,
+    Height,
+    NumberOfTags,
+    ModifiedTime,
+}
+
+/// Options for selecting the sauces that are used for a file.
+/// Defaults can be set in the `[matching]` section of the config
+#[derive(clap::Args, Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MatchOptions {
+    /// The minimum similarity for a sauce to be used [default: 80]
+    #[clap(long)]
+    pub min_similarity: Option<f32>,
+
+    /// Minimum similarities for specific indexes. Can only be set in the config
+    #[clap(skip)]
+    pub index_similarity: HashMap<SauceIndex, f32>,
+
+    /// The strategy used to select sauces from all sufficiently similar results [default: best]
+    #[clap(long, value_enum)]
+    pub strategy: Option<MatchStrategy>,
+
+    /// The indexes preferred by the priority strategy, starting with the most trusted one
+    #[clap(long, value_enum)]
+    pub priority: Vec<SauceIndex>,
+
+    /// The number of top results that need to agree on the source with the agreement strategy [default: 2]
+    #[clap(long)]
+    pub agreement_count: Option<usize>,
+}
+
+impl MatchOptions {
+    /// Fills all options that haven't been set with the given defaults
+    pub fn or(self, defaults: MatchOptions) -> Self {
+        Self {
+            min_similarity: self.min_similarity.or(defaults.min_similarity),
+            index_similarity: if self.index_similarity.is_empty() {
+                defaults.index_similarity
+            } else {
+                self.index_similarity
+            },
+            strategy: self.strategy.or(defaults.strategy),
+            priority: if self.priority.is_empty() {
+                defaults.priority
+            } else {
+                self.priority
+            },
+            agreement_count: self.agreement_count.or(defaults.agreement_count),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MatchStrategy {
+    /// Uses all results ordered by their similarity
+    #[default]
+    Best,
+    /// Uses the best result of the most trusted index
+    Priority,
+    /// Uses the results only if the top results point to the same source
+    Agreement,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -61,6 +260,100 @@ pub struct ImportUrlsOptions {
     /// A list of urls to import
     #[clap(short, long)]
     pub urls: Option<Vec<String>>,
+
+    /// The tag service the tags will be assigned to
+    #[clap(long, default_value = "my tags")]
+    pub tag_service: String,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ImportMixedUrlsOptions {
+    #[clap(flatten)]
+    pub urls: ImportUrlsOptions,
+
+    #[clap(flatten)]
+    pub pages: PageOptions,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ImportRedditListingOptions {
+    /// The listing to import, e.g. `r/pics`, `u/someone`, `u/someone/m/multi`,
+    /// `u/someone/saved` or the url of one of them.
+    /// Saved posts require a reddit client_id and refresh_token in the config
+    pub listing: String,
+
+    /// The order the posts are retrieved in
+    #[clap(long, value_enum, default_value = "new")]
+    pub sort: ListingSort,
+
+    /// The time range of posts for the top and controversial orders
+    #[clap(long, value_enum)]
+    pub time: Option<ListingTime>,
+
+    /// The maximum number of posts to import
+    #[clap(long)]
+    pub limit: Option<usize>,
+
+    /// The tag service the tags will be assigned to
+    #[clap(long, default_value = "my tags")]
+    pub tag_service: String,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ListingSort {
+    Hot,
+    New,
+    Top,
+    Rising,
+    Controversial,
+}
+
+impl ListingSort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListingSort::Hot => "hot",
+            ListingSort::New => "new",
+            ListingSort::Top => "top",
+            ListingSort::Rising => "rising",
+            ListingSort::Controversial => "controversial",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ListingTime {
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+    All,
+}
+
+impl ListingTime {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListingTime::Hour => "hour",
+            ListingTime::Day => "day",
+            ListingTime::Week => "week",
+            ListingTime::Month => "month",
+            ListingTime::Year => "year",
+            ListingTime::All => "all",
+        }
+    }
+}
+
+/// Options for pixiv works with multiple pages
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct PageOptions {
+    /// Imports every page of pixiv works instead of only the work url.
+    /// Pages of R-18 works can only be found with a pixiv session_id in the config
+    #[clap(long)]
+    pub all_pages: bool,
+
+    /// Adds a `page:` tag with the page number to matched files and imported pages
+    #[clap(long)]
+    pub page_tag: bool,
 }
 
 #[derive(Parser, Debug, Clone)]
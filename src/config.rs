@@ -1,8 +1,13 @@
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
 
-use crate::{error::Result, utils::get_config_dir};
+use crate::{
+    args::{MatchOptions, SearchOptions},
+    error::Result,
+    utils::{get_config_dir, journal::FileOutcome, pixiv::ORIGINAL_LANGUAGE, saucenao::SauceIndex},
+};
+use hydrus_api::wrapper::tag::Tag;
 use std::fs;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -10,6 +15,22 @@ pub struct Config {
     pub hydrus: HydrusConfig,
     pub saucenao: Option<SauceNaoConfig>,
     pub twitter: Option<TwitterConfig>,
+    #[serde(default)]
+    pub booru: BooruConfig,
+    #[serde(default)]
+    pub pixiv: PixivConfig,
+    #[serde(default)]
+    pub reddit: RedditConfig,
+    #[serde(default)]
+    pub fedi: FediConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub lookup: LookupConfig,
+    #[serde(default)]
+    pub search: SearchOptions,
+    #[serde(default)]
+    pub matching: MatchOptions,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -21,6 +42,80 @@ pub struct HydrusConfig {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SauceNaoConfig {
     pub api_key: String,
+    /// The indexes results are used from. Results from all indexes are used if not set
+    pub databases: Option<Vec<SauceIndex>>,
+    /// What is uploaded to saucenao for a lookup
+    #[serde(default)]
+    pub upload: UploadMode,
+    /// Files larger than this are uploaded as thumbnails in auto upload mode
+    #[serde(default = "default_max_upload_mb")]
+    pub max_upload_mb: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UploadMode {
+    /// Uploads the thumbnail for files that exceed the maximum upload size
+    #[default]
+    Auto,
+    /// Always uploads the full file
+    File,
+    /// Always uploads the thumbnail generated by hydrus
+    Thumbnail,
+}
+
+fn default_max_upload_mb() -> u64 {
+    10
+}
+
+/// Tags that are assigned to files depending on the outcome of the lookup
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LookupConfig {
+    /// Assigned to all files that have been processed without errors
+    pub finish_tag: Option<String>,
+    /// Assigned to files without any sauce
+    pub no_match_tag: Option<String>,
+    /// Assigned to files that only have sauces with a low similarity
+    pub low_similarity_tag: Option<String>,
+    /// Assigned to files whose sauces don't agree on a single source
+    pub ambiguous_tag: Option<String>,
+    /// Assigned to files that failed to be processed
+    pub error_tag: Option<String>,
+}
+
+impl LookupConfig {
+    /// Fills all tags that haven't been set with the given defaults
+    pub fn or(self, defaults: LookupConfig) -> Self {
+        Self {
+            finish_tag: self.finish_tag.or(defaults.finish_tag),
+            no_match_tag: self.no_match_tag.or(defaults.no_match_tag),
+            low_similarity_tag: self.low_similarity_tag.or(defaults.low_similarity_tag),
+            ambiguous_tag: self.ambiguous_tag.or(defaults.ambiguous_tag),
+            error_tag: self.error_tag.or(defaults.error_tag),
+        }
+    }
+
+    /// Returns the tags that should be assigned for the given outcome
+    pub fn tags_for_outcome(&self, outcome: FileOutcome) -> Vec<Tag> {
+        let outcome_tag = match outcome {
+            FileOutcome::Tagged => None,
+            FileOutcome::NoSauce => self.no_match_tag.as_ref(),
+            FileOutcome::LowSimilarity => self.low_similarity_tag.as_ref(),
+            FileOutcome::Ambiguous => self.ambiguous_tag.as_ref(),
+            FileOutcome::Error => self.error_tag.as_ref(),
+        };
+        let finish_tag = if outcome == FileOutcome::Error {
+            None
+        } else {
+            self.finish_tag.as_ref()
+        };
+
+        finish_tag
+            .into_iter()
+            .chain(outcome_tag)
+            .map(Tag::from)
+            .collect()
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -29,6 +124,243 @@ pub struct TwitterConfig {
     pub consumer_secret: String,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BooruConfig {
+    /// Maps booru tag categories to hydrus namespaces.
+    /// An empty namespace adds the tags without namespace and `-` ignores the category
+    #[serde(default = "default_booru_namespaces")]
+    pub namespaces: HashMap<String, String>,
+    pub gelbooru_api_key: Option<String>,
+    pub gelbooru_user_id: Option<String>,
+}
+
+impl Default for BooruConfig {
+    fn default() -> Self {
+        Self {
+            namespaces: default_booru_namespaces(),
+            gelbooru_api_key: None,
+            gelbooru_user_id: None,
+        }
+    }
+}
+
+fn default_booru_namespaces() -> HashMap<String, String> {
+    [
+        ("artist", "creator"),
+        ("circle", "creator"),
+        ("copyright", "series"),
+        ("character", "character"),
+        ("species", "species"),
+        ("meta", "meta"),
+        ("general", ""),
+        ("deprecated", "-"),
+        ("faults", "-"),
+        ("invalid", "-"),
+    ]
+    .into_iter()
+    .map(|(category, namespace)| (category.to_string(), namespace.to_string()))
+    .collect()
+}
+
+/// Rules applied to all tags before they are sent to hydrus.
+/// They are read from a separate `rules.toml` file
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RulesConfig {
+    /// Converts all tags to lowercase
+    pub lowercase: bool,
+    /// Maps tags to the tag they are replaced with
+    pub siblings: HashMap<String, String>,
+    pub rename: Vec<RenameRule>,
+    pub namespace: Vec<NamespaceRule>,
+    /// Patterns of tags that are dropped
+    pub blacklist: Vec<String>,
+}
+
+/// Replaces all tags matching the pattern with the replacement
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RenameRule {
+    pub pattern: String,
+    pub replacement: String,
+}
+
+/// Assigns the namespace to all tags without a namespace matching the pattern
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NamespaceRule {
+    pub pattern: String,
+    pub namespace: String,
+}
+
+impl RulesConfig {
+    pub fn read() -> Result<Self> {
+        let rules_file_path = get_config_dir()?.join(PathBuf::from("rules.toml"));
+
+        if !rules_file_path.exists() {
+            fs::write(&rules_file_path, include_str!("assets/rules.toml"))?;
+        }
+        let settings = config::Config::builder()
+            .add_source(config::File::with_name(rules_file_path.to_str().unwrap()))
+            .build()?;
+
+        Ok(settings.try_deserialize()?)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PixivConfig {
+    /// The languages tags are added in, ordered by preference.
+    /// Besides translation languages `original` and `romaji` can be used
+    #[serde(default = "default_pixiv_languages")]
+    pub languages: Vec<String>,
+    /// Adds the tag in every available language instead of only the first one
+    #[serde(default)]
+    pub all_languages: bool,
+    /// Namespaces assigned to tags in the given languages
+    #[serde(default)]
+    pub language_namespaces: HashMap<String, String>,
+    /// The `PHPSESSID` cookie of a logged in pixiv session.
+    /// Required for retrieving the pages of R-18 works.
+    /// It isn't serialized so that it doesn't end up in the cache settings
+    #[serde(default, skip_serializing)]
+    pub session_id: Option<String>,
+}
+
+impl Default for PixivConfig {
+    fn default() -> Self {
+        Self {
+            languages: default_pixiv_languages(),
+            all_languages: false,
+            language_namespaces: HashMap::new(),
+            session_id: None,
+        }
+    }
+}
+
+fn default_pixiv_languages() -> Vec<String> {
+    vec!["en".to_string(), ORIGINAL_LANGUAGE.to_string()]
+}
+
+/// Tags and notes added to files imported from reddit posts.
+/// Namespaces and tags set to an empty string are not added
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RedditConfig {
+    pub subreddit_namespace: String,
+    pub creator_namespace: String,
+    pub title_namespace: String,
+    pub flair_namespace: String,
+    /// Namespace of the date the post was created on
+    pub date_namespace: String,
+    /// Tag added to posts marked as nsfw
+    pub nsfw_tag: String,
+    /// Stores the text of self posts as a note
+    pub selftext_note: bool,
+    /// Client id used to expand imgur albums into their images
+    pub imgur_client_id: Option<String>,
+    /// Imports videos with audio with the post url instead of the silent fallback mp4
+    pub video_post_url: bool,
+    /// Client id of a reddit app. Requests are sent to the oauth api when it is set
+    pub client_id: Option<String>,
+    /// Client secret of script and web apps
+    pub client_secret: Option<String>,
+    /// Refresh token used to act as a user, e.g. for importing saved posts
+    pub refresh_token: Option<String>,
+    /// The user agent sent with requests
+    pub user_agent: Option<String>,
+}
+
+impl Default for RedditConfig {
+    fn default() -> Self {
+        Self {
+            subreddit_namespace: "subreddit".to_string(),
+            creator_namespace: "creator".to_string(),
+            title_namespace: "title".to_string(),
+            flair_namespace: "flair".to_string(),
+            date_namespace: String::new(),
+            nsfw_tag: "meta:nsfw".to_string(),
+            selftext_note: false,
+            imgur_client_id: None,
+            video_post_url: false,
+            client_id: None,
+            client_secret: None,
+            refresh_token: None,
+            user_agent: None,
+        }
+    }
+}
+
+/// Tags and notes added to files imported from fediverse posts.
+/// Empty values disable tags the same way as in [`RedditConfig`]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FediConfig {
+    /// Namespace of the `@user@instance` handle of the author
+    pub creator_namespace: String,
+    /// Adds the hashtags of posts as tags
+    pub hashtags: bool,
+    /// Namespace of hashtags. Hashtags are added without a namespace when empty
+    pub hashtag_namespace: String,
+    /// Namespace of the content warning of the post
+    pub content_warning_namespace: String,
+    /// Namespace of the date the post was published on
+    pub date_namespace: String,
+    /// Tag added to posts marked as sensitive
+    pub sensitive_tag: String,
+    /// Stores the text of posts as a note
+    pub content_note: bool,
+    /// Stores the description of attachments as a note
+    pub alt_text_note: bool,
+}
+
+impl Default for FediConfig {
+    fn default() -> Self {
+        Self {
+            creator_namespace: "creator".to_string(),
+            hashtags: true,
+            hashtag_namespace: String::new(),
+            content_warning_namespace: "content warning".to_string(),
+            date_namespace: String::new(),
+            sensitive_tag: "meta:sensitive".to_string(),
+            content_note: false,
+            alt_text_note: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Number of days saucenao results are cached for
+    #[serde(default = "default_sauce_ttl_days")]
+    pub sauce_ttl_days: u64,
+    /// Number of days tags retrieved from sources are cached for
+    #[serde(default = "default_source_ttl_days")]
+    pub source_ttl_days: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            sauce_ttl_days: default_sauce_ttl_days(),
+            source_ttl_days: default_source_ttl_days(),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_sauce_ttl_days() -> u64 {
+    30
+}
+
+fn default_source_ttl_days() -> u64 {
+    7
+}
+
 impl Config {
     pub fn read() -> Result<Self> {
         let config_dir = get_config_dir()?;
@@ -56,3 +388,65 @@ impl Config {
             .expect("No saucenao key configured. Please add one to the config file.")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tag_names(tags: Vec<Tag>) -> Vec<String> {
+        tags.iter().map(Tag::to_string).collect()
+    }
+
+    #[test]
+    fn it_assigns_tags_for_outcomes() {
+        let lookup = LookupConfig {
+            finish_tag: Some("meta:tagged".into()),
+            no_match_tag: Some("meta:no sauce".into()),
+            low_similarity_tag: Some("meta:low similarity".into()),
+            ambiguous_tag: Some("meta:ambiguous".into()),
+            error_tag: Some("meta:failed".into()),
+        };
+
+        assert_eq!(
+            tag_names(lookup.tags_for_outcome(FileOutcome::Tagged)),
+            vec!["meta:tagged"]
+        );
+        assert_eq!(
+            tag_names(lookup.tags_for_outcome(FileOutcome::NoSauce)),
+            vec!["meta:tagged", "meta:no sauce"]
+        );
+        assert_eq!(
+            tag_names(lookup.tags_for_outcome(FileOutcome::LowSimilarity)),
+            vec!["meta:tagged", "meta:low similarity"]
+        );
+        assert_eq!(
+            tag_names(lookup.tags_for_outcome(FileOutcome::Ambiguous)),
+            vec!["meta:tagged", "meta:ambiguous"]
+        );
+        // failed files are retried later so they don't get the finish tag
+        assert_eq!(
+            tag_names(lookup.tags_for_outcome(FileOutcome::Error)),
+            vec!["meta:failed"]
+        );
+    }
+
+    #[test]
+    fn it_prefers_command_line_tags() {
+        let config = LookupConfig {
+            finish_tag: Some("meta:tagged".into()),
+            no_match_tag: Some("meta:no sauce".into()),
+            ..Default::default()
+        };
+        let args = LookupConfig {
+            finish_tag: Some("meta:done".into()),
+            ..Default::default()
+        };
+        let lookup = args.or(config);
+
+        assert_eq!(
+            tag_names(lookup.tags_for_outcome(FileOutcome::NoSauce)),
+            vec!["meta:done", "meta:no sauce"]
+        );
+        assert!(lookup.tags_for_outcome(FileOutcome::Error).is_empty());
+    }
+}
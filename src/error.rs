@@ -22,16 +22,44 @@ pub enum Error {
     #[error(transparent)]
     Reqwest(#[from] reqwest::Error),
 
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
     #[error("Error in config {0}")]
     Config(#[from] config::ConfigError),
 
     #[error(transparent)]
     Twitter(#[from] egg_mode::error::Error),
 
+    #[error("The daily saucenao search limit has been reached")]
+    DailyLimitReached,
+
+    #[error(transparent)]
+    Reddit(#[from] RedditError),
+
     #[error("{0}")]
     String(String),
 }
 
+/// Reasons a reddit url can't be imported
+#[derive(Error, Debug)]
+pub enum RedditError {
+    #[error("The post {0} has been removed")]
+    PostRemoved(String),
+
+    #[error("{0} doesn't exist or has been deleted")]
+    NotFound(String),
+
+    #[error("The comment {0} can't be resolved to its post")]
+    CommentLink(String),
+
+    #[error("{url} can't be accessed because the subreddit is {reason}")]
+    Private { url: String, reason: String },
+
+    #[error("Unexpected response from reddit for {url}: {message}")]
+    UnexpectedResponse { url: String, message: String },
+}
+
 impl From<RustNaoError> for Error {
     fn from(e: RustNaoError) -> Self {
         Self::RustNao(e.to_string())
@@ -4,164 +4,273 @@ mod error;
 mod operations;
 pub mod utils;
 
-use crate::config::Config;
-use crate::config::SauceNaoConfig;
-use crate::error::Result;
-use crate::operations::find_and_send_fedi_posts::find_and_send_fedi_posts;
-use crate::operations::find_and_send_tags::find_and_send_tags;
+use crate::config::{Config, LookupConfig, RulesConfig};
+use crate::error::{Error, Result};
+use crate::operations::find_and_send_tags::{find_and_send_tags, TagFetcher};
 use crate::operations::find_and_send_urls::find_and_send_urls;
+use crate::operations::import_reddit_listing::import_reddit_listing;
+use crate::operations::import_urls::{import_urls, import_urls_with};
 use args::*;
 use clap::Parser;
 use hydrus_api::api_core::common::FileIdentifier;
-use hydrus_api::api_core::endpoints::adding_tags::TagAction;
 use hydrus_api::wrapper::service::ServiceName;
 use hydrus_api::wrapper::tag::Tag;
 use hydrus_api::{Client, Hydrus};
-use operations::find_and_send_reddit_posts::find_and_send_reddit_posts;
-use pixiv_rs::PixivClient;
-use rustnao::{Handler, HandlerBuilder};
 use std::str::FromStr;
-use tempdir::TempDir;
 use tokio::fs::File;
 use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::time::{Duration, Instant};
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::EnvFilter;
-use utils::urls::find_url_type;
-use utils::urls::UrlType;
+use utils::booru::BooruClient;
+use utils::cache::LookupCache;
+use utils::changes::{FileChanges, HydrusWriter};
+use utils::fedi::FediHandler;
+use utils::journal::{FileOutcome, Journal};
+use utils::pixiv::PixivTagger;
+use utils::reddit::{RedditHandler, RedditListing};
+use utils::rules::TagRules;
+use utils::saucenao::SauceLookup;
+use utils::search::build_search;
+use utils::settings_hash;
+use utils::sources::{SourceHandler, SourceRegistry};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     color_eyre::install().unwrap();
-    init_logger();
     let args: Args = Args::parse();
+    init_logger(args.dry_run);
     let config = Config::read().expect("Failed to read configuration");
     tracing::debug!("args: {args:?}");
-    let hydrus = Hydrus::new(Client::new(&config.hydrus.api_url, &config.hydrus.api_key));
+    let client = Client::new(&config.hydrus.api_url, &config.hydrus.api_key);
+    let hydrus = Hydrus::new(client.clone());
+    let rules = RulesConfig::read()
+        .and_then(TagRules::new)
+        .expect("Failed to read tag rules");
+    let writer = HydrusWriter::new(Hydrus::new(client), rules, args.dry_run);
 
-    match args.subcommand {
-        Command::FindAndSendUrl(opt) => {
-            send_tags_or_urls(opt, config.into_saucenao(), hydrus, true).await
-        }
+    let result = match args.subcommand {
+        Command::FindAndSendUrl(opt) => send_tags_or_urls(opt, config, hydrus, &writer, true).await,
         Command::FindAndSendTags(opt) => {
-            send_tags_or_urls(opt, config.into_saucenao(), hydrus, false).await
+            send_tags_or_urls(opt, config, hydrus, &writer, false).await
+        }
+        Command::ImportRedditPosts(opt) => import_reddit_posts(opt, config, hydrus, &writer).await,
+        Command::ImportRedditListing(opt) => import_listing(opt, config, hydrus, &writer).await,
+        Command::ImportFediPosts(opt) => {
+            let fedi = FediHandler::new(config.fedi);
+            import_posts(opt, hydrus, &writer, &fedi).await
         }
-        Command::ImportRedditPosts(opt) => import_reddit_posts(opt, hydrus).await,
-        Command::ImportFediPosts(opt) => import_fedi_posts(opt, hydrus).await,
-        Command::ImportUrls(opt) => import_urls(opt, hydrus).await,
-        Command::Tag(opt) => tag_files(opt, hydrus).await,
+        Command::ImportUrls(opt) => import_mixed_urls(opt, config, hydrus, &writer).await,
+        Command::Tag(opt) => tag_files(opt, hydrus, &writer).await,
+    };
+
+    if let Err(e) = writer.finish().await {
+        tracing::error!("Failed to apply urls and notes of imported files: {e}");
     }
-    .expect("Failed to send tags or urls");
+    // the report is printed before failing so that the changes made until then are shown
+    writer
+        .print_report(args.dry_run_format)
+        .expect("Failed to print dry run report");
+    result.expect("Failed to send tags or urls");
 }
 
-fn init_logger() {
+/// Logs to stdout or to stderr in dry run mode so that the report on stdout can be parsed
+fn init_logger(dry_run: bool) {
     const DEFAULT_ENV_FILTER: &str = "info";
     let filter_string =
         std::env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_ENV_FILTER.to_string());
     let env_filter =
         EnvFilter::from_str(&*filter_string).expect("failed to parse env filter string");
-    tracing_subscriber::fmt::SubscriberBuilder::default()
+    let builder = tracing_subscriber::fmt::SubscriberBuilder::default()
         .with_env_filter(env_filter)
-        .with_writer(std::io::stdout)
         .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-        .compact()
-        .init();
+        .compact();
+
+    if dry_run {
+        builder.with_writer(std::io::stderr).init();
+    } else {
+        builder.with_writer(std::io::stdout).init();
+    }
 }
 
-#[tracing::instrument(level = "debug", skip(hydrus))]
+#[tracing::instrument(level = "debug", skip(hydrus, writer))]
 async fn send_tags_or_urls(
     opt: LookupOptions,
-    saucenao_cfg: SauceNaoConfig,
+    config: Config,
     hydrus: Hydrus,
+    writer: &HydrusWriter,
     send_urls: bool,
 ) -> Result<()> {
-    let pixiv = PixivClient::new();
+    let fetcher = TagFetcher {
+        pixiv: PixivTagger::new(config.pixiv.clone()),
+        booru: BooruClient::new(config.booru.clone())?,
+    };
+    let outcome_tags = LookupConfig {
+        finish_tag: opt.finish_tag,
+        no_match_tag: opt.no_match_tag,
+        low_similarity_tag: opt.low_similarity_tag,
+        ambiguous_tag: opt.ambiguous_tag,
+        error_tag: opt.error_tag,
+    }
+    .or(config.lookup.clone());
+    let config_search = config.search.clone();
+    let matching = opt.matching.or(config.matching.clone());
+    let cache = LookupCache::new(&config.cache, opt.refresh)?
+        .with_source_settings(&(&config.booru.namespaces, &config.pixiv))?;
+    let hydrus_config = config.hydrus.clone();
+    let lookup = SauceLookup::new(config.into_saucenao(), &hydrus_config, matching, cache)?;
 
-    let handler = HandlerBuilder::new()
-        .api_key(&saucenao_cfg.api_key)
-        .min_similarity(80)
-        .db(Handler::PIXIV)
-        .build();
+    let search = opt.search.or(config_search);
+    let command = if send_urls { "send-url" } else { "send-tags" };
+    // runs with different system predicates search different files and need their own journal
+    let job_id = format!(
+        "{command}-{}-{}-{}",
+        opt.tag_service,
+        opt.tags.join("-"),
+        settings_hash(&search)?
+    );
+    let mut journal = Journal::open(&job_id, opt.resume, !writer.is_dry_run())?;
 
     let tags = opt.tags.into_iter().map(Tag::from).collect();
     let service = ServiceName(opt.tag_service);
 
-    let files = hydrus.search().add_tags(tags).run().await.unwrap();
+    let files = build_search(&hydrus, tags, &search)?.run().await?;
     tracing::info!("Found {} files", files.len());
-    let tmpdir = TempDir::new("hydrus-files").unwrap();
 
-    let sleep_duration = Duration::from_secs(6);
     let total_files = files.len();
     let service_key = hydrus.get_service_key(service.into()).await?;
 
+    if opt.resume {
+        tracing::info!(
+            "Resuming job. {} files have already been processed",
+            journal.completed_count()
+        );
+    }
+
     for (i, mut file) in files.into_iter().enumerate() {
-        let start = Instant::now();
-        tracing::info!("Searching for file {} out of {}", i + 1, total_files);
+        let hash = file.hash().await?;
 
-        if send_urls {
-            let _ = find_and_send_urls(&hydrus, &handler, &tmpdir, &mut file).await;
+        if journal.is_completed(&hash) {
+            tracing::debug!("Skipping already processed file {hash}");
+            continue;
+        }
+        let mime = file.mime().await.ok().map(|m| m.essence_str().to_owned());
+        tracing::info!(
+            "Searching for file {} out of {} ({})",
+            i + 1,
+            total_files,
+            mime.as_deref().unwrap_or("unknown type")
+        );
+
+        let result = if send_urls {
+            find_and_send_urls(
+                writer,
+                &lookup,
+                &fetcher.pixiv,
+                &service_key,
+                &opt.pages,
+                &mut file,
+            )
+            .await
         } else {
-            let _ = find_and_send_tags(
-                opt.finish_tag.as_ref(),
-                &handler,
-                &pixiv,
+            find_and_send_tags(
+                writer,
+                &lookup,
+                &fetcher,
                 &service_key,
-                &tmpdir,
+                &opt.pages,
                 &mut file,
             )
-            .await;
-        }
-        let elapsed = start.elapsed();
+            .await
+        };
 
-        if elapsed.as_secs() < 8 && sleep_duration > elapsed {
-            tokio::time::sleep(sleep_duration - elapsed).await; // rate limit of 6# / 30s
+        let outcome = match result {
+            Err(Error::DailyLimitReached) => {
+                tracing::warn!(
+                    "Daily saucenao search limit reached after {} of {} files. \
+                    {} files remain unprocessed. Run the command again with --resume \
+                    once the limit has reset.",
+                    i,
+                    total_files,
+                    total_files - i
+                );
+                break;
+            }
+            Err(e) => {
+                tracing::error!("Failed to process file {}: {:?}", hash, e);
+                journal.record(&hash, FileOutcome::Error, mime, Some(e.to_string()))?;
+                FileOutcome::Error
+            }
+            Ok(outcome) => {
+                journal.record(&hash, outcome, mime, None)?;
+                outcome
+            }
+        };
+        let mut changes = FileChanges::default();
+        changes.add_tags(&service_key, outcome_tags.tags_for_outcome(outcome));
+
+        // outcome tags are used to find files again and must not be renamed or blacklisted
+        if let Err(e) = writer.apply_unchanged(&mut file, changes).await {
+            tracing::error!("Failed to assign outcome tags to file {}: {}", hash, e);
         }
     }
+    tracing::info!("Job journal written to {:?}", journal.path());
 
     Ok(())
 }
 
-#[tracing::instrument(level = "debug", skip(hydrus))]
-async fn import_reddit_posts(opt: ImportUrlsOptions, hydrus: Hydrus) -> Result<()> {
+#[tracing::instrument(level = "debug", skip(hydrus, writer, handler))]
+async fn import_posts(
+    opt: ImportUrlsOptions,
+    hydrus: Hydrus,
+    writer: &HydrusWriter,
+    handler: &dyn SourceHandler,
+) -> Result<()> {
+    let service_key = hydrus
+        .get_service_key(ServiceName(opt.tag_service.clone()).into())
+        .await?;
     let urls = get_urls_from_args(opt).await?;
-    find_and_send_reddit_posts(&hydrus, urls).await
+    import_urls_with(writer, handler, &service_key, urls).await
 }
 
-#[tracing::instrument(level = "debug", skip(hydrus))]
-async fn import_fedi_posts(opt: ImportUrlsOptions, hydrus: Hydrus) -> Result<()> {
-    let urls = get_urls_from_args(opt).await?;
-    find_and_send_fedi_posts(&hydrus, urls).await
+#[tracing::instrument(level = "debug", skip(config, hydrus, writer))]
+async fn import_reddit_posts(
+    opt: ImportUrlsOptions,
+    config: Config,
+    hydrus: Hydrus,
+    writer: &HydrusWriter,
+) -> Result<()> {
+    let reddit = RedditHandler::new(config.reddit)?;
+    import_posts(opt, hydrus, writer, &reddit).await
 }
 
-async fn import_urls(opt: ImportUrlsOptions, hydrus: Hydrus) -> Result<()> {
-    let urls = get_urls_from_args(opt).await?;
-    let mut reddit_urls = Vec::new();
-    let mut fedi_urls = Vec::new();
-    let mut unknown_urls = Vec::new();
-
-    for url in urls {
-        match find_url_type(&url).await {
-            UrlType::Reddit => reddit_urls.push(url),
-            UrlType::Fedi => fedi_urls.push(url),
-            UrlType::Other => {
-                tracing::warn!("Unknown url type {url}");
-                unknown_urls.push(url)
-            }
-        }
-    }
-    tracing::info!("Importing reddit posts...");
-    find_and_send_reddit_posts(&hydrus, reddit_urls).await?;
-    find_and_send_fedi_posts(&hydrus, fedi_urls).await?;
-
-    tracing::info!("Importing unknown urls...");
-
-    for url in unknown_urls {
-        if let Err(e) = hydrus.import().url(&url).run().await {
-            tracing::error!("Failed to import {url}: {e}")
-        }
-    }
+#[tracing::instrument(level = "debug", skip(config, hydrus, writer))]
+async fn import_listing(
+    opt: ImportRedditListingOptions,
+    config: Config,
+    hydrus: Hydrus,
+    writer: &HydrusWriter,
+) -> Result<()> {
+    let listing = RedditListing::parse(&opt.listing, opt.sort, opt.time)?;
+    let reddit = RedditHandler::new(config.reddit)?;
+    let service_key = hydrus
+        .get_service_key(ServiceName(opt.tag_service).into())
+        .await?;
+    import_reddit_listing(writer, &reddit, &service_key, &listing, opt.limit).await
+}
 
-    Ok(())
+#[tracing::instrument(level = "debug", skip(config, hydrus, writer))]
+async fn import_mixed_urls(
+    opt: ImportMixedUrlsOptions,
+    config: Config,
+    hydrus: Hydrus,
+    writer: &HydrusWriter,
+) -> Result<()> {
+    let registry = SourceRegistry::from_config(&config, opt.pages)?;
+    let service_key = hydrus
+        .get_service_key(ServiceName(opt.urls.tag_service.clone()).into())
+        .await?;
+    let urls = get_urls_from_args(opt.urls).await?;
+    import_urls(writer, &registry, &service_key, urls).await
 }
 
 async fn get_urls_from_args(opt: ImportUrlsOptions) -> Result<Vec<String>> {
@@ -184,18 +293,17 @@ async fn get_urls_from_args(opt: ImportUrlsOptions) -> Result<Vec<String>> {
     Ok(urls)
 }
 
-async fn tag_files(opt: TagOptions, hydrus: Hydrus) -> Result<()> {
+async fn tag_files(opt: TagOptions, hydrus: Hydrus, writer: &HydrusWriter) -> Result<()> {
     let tags = opt.tags.into_iter().map(Tag::from).collect::<Vec<_>>();
     let service_key = hydrus
         .get_service_key(ServiceName(opt.tag_service).into())
         .await?;
 
     for file in opt.files {
-        hydrus
-            .file(FileIdentifier::hash(file))
-            .await?
-            .add_tags(service_key.to_owned(), tags.clone())
-            .await?;
+        let mut file = hydrus.file(FileIdentifier::hash(file)).await?;
+        let mut changes = FileChanges::default();
+        changes.add_tags(&service_key, tags.clone());
+        writer.apply(&mut file, changes).await?;
     }
     Ok(())
 }
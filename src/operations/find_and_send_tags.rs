@@ -1,69 +1,189 @@
+use std::collections::HashSet;
+
 use crate::{
+    args::PageOptions,
     error::Result,
-    utils::pixiv::{get_pixiv_url, get_sauces_for_file, get_tags_for_sauce},
+    utils::{
+        booru::{Booru, BooruClient},
+        cache::LookupCache,
+        changes::{FileChanges, HydrusWriter, SourceMetadata},
+        journal::FileOutcome,
+        pixiv::{get_pixiv_url, page_of_sauce, page_tag, PixivTagger},
+        saucenao::{SauceIndex, SauceLookup, SauceMatch},
+    },
 };
 use hydrus_api::wrapper::hydrus_file::HydrusFile;
-use pixiv_rs::PixivClient;
-use rustnao::{Handler, Sauce};
-use tempdir::TempDir;
+use rustnao::Sauce;
+
+/// The clients used to retrieve tags for a sauce
+pub struct TagFetcher {
+    pub pixiv: PixivTagger,
+    pub booru: BooruClient,
+}
 
 #[tracing::instrument(level = "debug", skip_all)]
 pub async fn find_and_send_tags(
-    finish_tag: Option<&String>,
-    handler: &Handler,
-    pixiv: &PixivClient,
+    writer: &HydrusWriter,
+    lookup: &SauceLookup,
+    fetcher: &TagFetcher,
     service_key: &str,
-    tmpdir: &TempDir,
-    mut file: &mut HydrusFile,
-) -> Result<()> {
-    if let Err(e) = search_and_assign_tags(&handler, &pixiv, service_key, &tmpdir, &mut file).await
-    {
-        let hash = file.hash().await.unwrap();
-        tracing::error!("Failed to search tags to file {}: {:?}", hash, e);
-    } else if let Some(finish_tag) = finish_tag {
-        file.add_tags(service_key.to_owned(), vec![finish_tag.into()])
-            .await
-            .unwrap();
-    }
+    pages: &PageOptions,
+    file: &mut HydrusFile,
+) -> Result<FileOutcome> {
+    let (outcome, changes) = search_tags(lookup, fetcher, service_key, pages, file).await?;
+    writer.apply(file, changes).await?;
 
-    Ok(())
+    Ok(outcome)
 }
 
 #[tracing::instrument(level = "debug", skip_all)]
-async fn search_and_assign_tags(
-    handler: &Handler,
-    pixiv: &PixivClient,
+async fn search_tags(
+    lookup: &SauceLookup,
+    fetcher: &TagFetcher,
     service_key: &str,
-    tmpdir: &TempDir,
-    mut file: &mut HydrusFile,
-) -> Result<()> {
+    pages: &PageOptions,
+    file: &mut HydrusFile,
+) -> Result<(FileOutcome, FileChanges)> {
     tracing::debug!("Getting tags for hydrus file {:?}", file.id);
-    let sauces = get_sauces_for_file(&handler, tmpdir, file).await?;
+    let sauces = match lookup.find_matches(file).await? {
+        SauceMatch::Found(sauces) => sauces,
+        SauceMatch::LowSimilarity => {
+            tracing::info!("Only found sauces with low similarity for {:?}", file.id);
+            return Ok((FileOutcome::LowSimilarity, FileChanges::default()));
+        }
+        SauceMatch::Ambiguous => {
+            tracing::info!("Found sauces for different sources for {:?}", file.id);
+            return Ok((FileOutcome::Ambiguous, FileChanges::default()));
+        }
+        SauceMatch::None => {
+            tracing::info!("No sauce for file {:?} found", file.id);
+            return Ok((FileOutcome::NoSauce, FileChanges::default()));
+        }
+    };
+
+    let (outcome, mut changes) =
+        get_tags_and_urls(fetcher, lookup.cache(), service_key, file, &sauces).await?;
 
-    assign_pixiv_tags_and_url(&pixiv, service_key, &mut file, &sauces).await
+    if pages.page_tag && outcome == FileOutcome::Tagged {
+        if let Some(page) = sauces.iter().find_map(page_of_sauce) {
+            changes.add_tags(service_key, vec![page_tag(page)]);
+        }
+    }
+
+    Ok((outcome, changes))
 }
 
 #[tracing::instrument(level = "debug", skip_all)]
-async fn assign_pixiv_tags_and_url(
-    pixiv: &&PixivClient,
+async fn get_tags_and_urls(
+    fetcher: &TagFetcher,
+    cache: &LookupCache,
     service_key: &str,
-    file: &mut &mut HydrusFile,
-    sauce: &Vec<Sauce>,
-) -> Result<()> {
+    file: &mut HydrusFile,
+    sauces: &[Sauce],
+) -> Result<(FileOutcome, FileChanges)> {
     let hash = file.hash().await?;
-    if let Some(url) = get_pixiv_url(&sauce) {
-        let tags = get_tags_for_sauce(&pixiv, url).await?;
-
-        if tags.len() > 0 {
-            tracing::info!("Found {} tags for file {:?}", tags.len(), hash);
-            file.add_tags(service_key.to_owned(), tags).await?;
-        } else {
-            tracing::info!("No tags for file {:?} found", hash);
+    let mut tags = Vec::new();
+    let mut changes = FileChanges::default();
+    let mut used_indexes = HashSet::new();
+    let mut last_error = None;
+
+    // sauces are ordered by similarity so only the best match of each index is used
+    for sauce in sauces {
+        let Some(index) = SauceIndex::of_sauce(sauce) else {
+            continue;
+        };
+        if used_indexes.contains(&index) {
+            continue;
         }
-        file.associate_urls(vec![url.to_string()]).await?;
+        let metadata = match get_metadata_for_index(fetcher, cache, index, sauce).await {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                // one unavailable source shouldn't discard the tags of the others
+                tracing::warn!(
+                    "Failed to retrieve tags from {:?} for file {}: {}",
+                    index,
+                    hash,
+                    e
+                );
+                last_error = Some(e);
+                continue;
+            }
+        };
+        if let Some((url, mut metadata)) = metadata {
+            tracing::debug!("Found {} tags on {:?}", metadata.tags.len(), index);
+            used_indexes.insert(index);
+            tags.append(&mut metadata.tags);
+            changes.notes.append(&mut metadata.notes);
+            changes.add_url(url);
+        }
+    }
+
+    if changes.urls.is_empty() {
+        if let Some(e) = last_error {
+            return Err(e);
+        }
+        tracing::info!("No supported source for file {:?} found", hash);
+        return Ok((FileOutcome::NoSauce, FileChanges::default()));
+    }
+    tags.sort_by_key(|t| t.to_string());
+    tags.dedup();
+
+    if !tags.is_empty() {
+        tracing::info!("Found {} tags for file {:?}", tags.len(), hash);
+    } else {
+        tracing::info!("No tags for file {:?} found", hash);
+    }
+    changes.add_tags(service_key, tags);
+
+    Ok((FileOutcome::Tagged, changes))
+}
+
+/// Fetches the tags and notes for the given sauce from the source of its index.
+/// Returns `None` if metadata can't be retrieved for this index
+async fn get_metadata_for_index(
+    fetcher: &TagFetcher,
+    cache: &LookupCache,
+    index: SauceIndex,
+    sauce: &Sauce,
+) -> Result<Option<(String, SourceMetadata)>> {
+    let url = if index == SauceIndex::Pixiv {
+        get_pixiv_url(sauce)
     } else {
-        tracing::info!("No pixiv post for file {:?} found", hash);
+        sauce.ext_urls.first()
+    };
+    let Some(url) = url else {
+        return Ok(None);
+    };
+    if let Some(metadata) = cache.source(url) {
+        return Ok(Some((url.to_owned(), metadata)));
+    }
+    let Some(metadata) = fetch_metadata_for_index(fetcher, index, sauce, url).await? else {
+        return Ok(None);
+    };
+    cache.store_source(url, &metadata)?;
+
+    Ok(Some((url.to_owned(), metadata)))
+}
+
+async fn fetch_metadata_for_index(
+    fetcher: &TagFetcher,
+    index: SauceIndex,
+    sauce: &Sauce,
+    url: &str,
+) -> Result<Option<SourceMetadata>> {
+    if index == SauceIndex::Pixiv {
+        let metadata = fetcher.pixiv.metadata_for_url(url).await?;
+        return Ok(Some(metadata));
+    }
+    if let Some(booru) = Booru::from_index(index) {
+        if let Some(id) = booru.post_id(sauce) {
+            let metadata = SourceMetadata {
+                tags: fetcher.booru.tags(booru, id).await?,
+                ..Default::default()
+            };
+            return Ok(Some(metadata));
+        }
     }
 
-    Ok(())
+    Ok(None)
 }
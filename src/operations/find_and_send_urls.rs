@@ -1,22 +1,74 @@
-use hydrus_api::{wrapper::hydrus_file::HydrusFile, Hydrus};
-use rustnao::Handler;
-use tempdir::TempDir;
+use std::collections::HashSet;
 
+use hydrus_api::wrapper::hydrus_file::HydrusFile;
+
+use crate::args::PageOptions;
 use crate::error::Result;
-use crate::utils::pixiv::{get_sauces_for_file, get_urls};
+use crate::operations::import_urls::import_media;
+use crate::utils::changes::{FileChanges, HydrusWriter};
+use crate::utils::journal::FileOutcome;
+use crate::utils::pixiv::{
+    get_pixiv_url, page_media, page_of_sauce, page_tag, pixiv_id_from_url, PixivTagger,
+};
+use crate::utils::saucenao::{SauceIndex, SauceLookup, SauceMatch};
 
 #[tracing::instrument(level = "debug", skip_all)]
 pub async fn find_and_send_urls(
-    hydrus: &Hydrus,
-    handler: &Handler,
-    tmpdir: &TempDir,
+    writer: &HydrusWriter,
+    lookup: &SauceLookup,
+    pixiv: &PixivTagger,
+    service_key: &str,
+    pages: &PageOptions,
     file: &mut HydrusFile,
-) -> Result<()> {
-    let sauces = get_sauces_for_file(&handler, tmpdir, file).await?;
-    let urls = get_urls(&sauces);
+) -> Result<FileOutcome> {
+    let sauces = match lookup.find_matches(file).await? {
+        SauceMatch::Found(sauces) => sauces,
+        SauceMatch::LowSimilarity => return Ok(FileOutcome::LowSimilarity),
+        SauceMatch::Ambiguous => return Ok(FileOutcome::Ambiguous),
+        SauceMatch::None => return Ok(FileOutcome::NoSauce),
+    };
+    let mut imported_works = HashSet::new();
+    let mut used_indexes = HashSet::new();
+    let mut urls = Vec::new();
+
+    // sauces are ordered by similarity so only the best match of each index is imported
+    for sauce in &sauces {
+        if !used_indexes.insert(sauce.index) {
+            continue;
+        }
+        let pixiv_id = if SauceIndex::of_sauce(sauce) == Some(SauceIndex::Pixiv) {
+            get_pixiv_url(sauce).and_then(|url| pixiv_id_from_url(url))
+        } else {
+            None
+        };
+        match pixiv_id {
+            Some(pixiv_id) if pages.all_pages => {
+                if imported_works.insert(pixiv_id) {
+                    let media = page_media(pixiv, pixiv_id, pages.page_tag).await?;
+                    import_media(writer, service_key, media).await?;
+                }
+            }
+            _ => {
+                if let Some(url) = sauce.ext_urls.first().filter(|url| !urls.contains(url)) {
+                    urls.push(url);
+                }
+            }
+        }
+    }
+
+    if urls.is_empty() && imported_works.is_empty() {
+        return Ok(FileOutcome::NoSauce);
+    }
     for url in urls {
-        hydrus.import().url(url).run().await?;
+        writer.import_url(url, FileChanges::default()).await?;
+    }
+    if pages.page_tag {
+        if let Some(page) = sauces.iter().find_map(page_of_sauce) {
+            let mut changes = FileChanges::default();
+            changes.add_tags(service_key, vec![page_tag(page)]);
+            writer.apply(file, changes).await?;
+        }
     }
 
-    Ok(())
+    Ok(FileOutcome::Tagged)
 }
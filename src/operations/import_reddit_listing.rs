@@ -0,0 +1,52 @@
+use crate::error::Result;
+use crate::operations::import_urls::import_media;
+use crate::utils::changes::HydrusWriter;
+use crate::utils::reddit::{RedditHandler, RedditListing};
+
+/// Pages through the listing and imports the media of every post
+#[tracing::instrument(level = "debug", skip(writer, reddit))]
+pub async fn import_reddit_listing(
+    writer: &HydrusWriter,
+    reddit: &RedditHandler,
+    service_key: &str,
+    listing: &RedditListing,
+    limit: Option<usize>,
+) -> Result<()> {
+    let mut after: Option<String> = None;
+    let mut imported_posts = 0;
+
+    'pages: loop {
+        let remaining = limit.map(|limit| limit.saturating_sub(imported_posts));
+        if remaining == Some(0) {
+            break;
+        }
+        let page = reddit
+            .listing_page(listing, after.as_deref(), remaining)
+            .await?;
+        tracing::info!(
+            "Found {} posts with media out of {} posts",
+            page.posts.len(),
+            page.post_count
+        );
+
+        for media in page.posts {
+            if limit.is_some_and(|limit| imported_posts >= limit) {
+                break 'pages;
+            }
+            imported_posts += 1;
+            tracing::info!("Importing post {}", imported_posts);
+
+            if let Err(e) = import_media(writer, service_key, media).await {
+                tracing::error!("Failed to import post: {}", e);
+            }
+        }
+
+        match page.after {
+            Some(next) if page.post_count > 0 => after = Some(next),
+            _ => break,
+        }
+    }
+    tracing::info!("Imported {} posts", imported_posts);
+
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use std::future::Future;
+
+use crate::error::Result;
+use crate::utils::changes::HydrusWriter;
+use crate::utils::sources::{ResolvedMedia, SourceHandler, SourceRegistry};
+use futures::{Stream, StreamExt};
+
+/// The number of urls that are resolved at the same time
+const RESOLVE_CONCURRENCY: usize = 2;
+
+/// Imports the urls with the matching handler of the registry.
+/// Urls without a handler are sent to hydrus as they are
+#[tracing::instrument(level = "debug", skip(writer, registry))]
+pub async fn import_urls(
+    writer: &HydrusWriter,
+    registry: &SourceRegistry,
+    service_key: &str,
+    urls: Vec<String>,
+) -> Result<()> {
+    let total_urls = urls.len();
+    let resolved = futures::stream::iter(urls).map(|url| async move {
+        let media = match registry.find_handler(&url).await {
+            Some(handler) => resolve(handler, &url).await,
+            None => {
+                tracing::warn!("Unknown url type {url}");
+                Ok(vec![ResolvedMedia {
+                    url: url.clone(),
+                    ..Default::default()
+                }])
+            }
+        };
+        (url, media)
+    });
+
+    import_resolved(writer, service_key, total_urls, resolved).await
+}
+
+/// Imports all urls with the given handler
+#[tracing::instrument(level = "debug", skip(writer, handler))]
+pub async fn import_urls_with(
+    writer: &HydrusWriter,
+    handler: &dyn SourceHandler,
+    service_key: &str,
+    urls: Vec<String>,
+) -> Result<()> {
+    let total_urls = urls.len();
+    let resolved = futures::stream::iter(urls).map(|url| async move {
+        let media = resolve(handler, &url).await;
+        (url, media)
+    });
+
+    import_resolved(writer, service_key, total_urls, resolved).await
+}
+
+/// Imports the media of each url in order while the following urls are resolved
+async fn import_resolved<S, F>(
+    writer: &HydrusWriter,
+    service_key: &str,
+    total_urls: usize,
+    resolved: S,
+) -> Result<()>
+where
+    S: Stream<Item = F>,
+    F: Future<Output = (String, Result<Vec<ResolvedMedia>>)>,
+{
+    let resolved = resolved.buffered(RESOLVE_CONCURRENCY);
+    futures::pin_mut!(resolved);
+    let mut index = 0;
+    let mut failed_urls = 0;
+
+    while let Some((url, media)) = resolved.next().await {
+        index += 1;
+        tracing::info!("Importing url {} of {}", index, total_urls);
+
+        let result = match media {
+            Ok(media) => import_media(writer, service_key, media).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            tracing::error!("Failed to import {}: {}", url, e);
+            failed_urls += 1;
+        }
+    }
+    report_failures(failed_urls, total_urls);
+
+    Ok(())
+}
+
+fn report_failures(failed_urls: usize, total_urls: usize) {
+    if failed_urls > 0 {
+        tracing::warn!("Failed to import {} of {} urls", failed_urls, total_urls);
+    }
+}
+
+async fn resolve(handler: &dyn SourceHandler, url: &str) -> Result<Vec<ResolvedMedia>> {
+    let media = handler.resolve(url).await?;
+    tracing::info!(
+        "Found {} files for {} url {}",
+        media.len(),
+        handler.name(),
+        url
+    );
+
+    Ok(media)
+}
+
+/// Imports the resolved media with their metadata
+pub async fn import_media(
+    writer: &HydrusWriter,
+    service_key: &str,
+    media: Vec<ResolvedMedia>,
+) -> Result<()> {
+    for media in media {
+        let url = media.url.clone();
+        let referer = media.referer.clone();
+        let changes = media.into_changes(service_key);
+
+        match referer {
+            Some(referer) => writer.import_download(&url, &referer, changes).await?,
+            None => writer.import_url(&url, changes).await?,
+        }
+    }
+
+    Ok(())
+}
@@ -1,4 +1,4 @@
-pub mod find_and_send_fedi_posts;
-pub mod find_and_send_reddit_posts;
 pub mod find_and_send_tags;
 pub mod find_and_send_urls;
+pub mod import_reddit_listing;
+pub mod import_urls;
@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+
+use crate::config::BooruConfig;
+use crate::error::Result;
+use crate::utils::saucenao::SauceIndex;
+use hydrus_api::wrapper::builders::tag_builder::TagBuilder;
+use hydrus_api::wrapper::tag::Tag;
+use reqwest::Client;
+use rustnao::Sauce;
+use serde::Deserialize;
+
+/// The booru sites tags can be fetched from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Booru {
+    Danbooru,
+    Gelbooru,
+    YandeRe,
+    Konachan,
+    E621,
+}
+
+impl Booru {
+    pub fn from_index(index: SauceIndex) -> Option<Self> {
+        let booru = match index {
+            SauceIndex::Danbooru => Self::Danbooru,
+            SauceIndex::Gelbooru => Self::Gelbooru,
+            SauceIndex::YandeRe => Self::YandeRe,
+            SauceIndex::Konachan => Self::Konachan,
+            SauceIndex::E621 => Self::E621,
+            _ => return None,
+        };
+
+        Some(booru)
+    }
+
+    /// The name of the field saucenao stores the post id in
+    fn id_field(&self) -> &'static str {
+        match self {
+            Booru::Danbooru => "danbooru_id",
+            Booru::Gelbooru => "gelbooru_id",
+            Booru::YandeRe => "yandere_id",
+            Booru::Konachan => "konachan_id",
+            Booru::E621 => "e621_id",
+        }
+    }
+
+    /// Returns the id of the post on this booru for the given sauce
+    pub fn post_id(&self, sauce: &Sauce) -> Option<u64> {
+        sauce
+            .additional_fields
+            .as_ref()?
+            .get(self.id_field())?
+            .as_u64()
+    }
+}
+
+/// A tag with the category it has on the booru
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CategorizedTag {
+    category: String,
+    name: String,
+}
+
+impl CategorizedTag {
+    fn new<S1: ToString, S2: ToString>(category: S1, name: S2) -> Self {
+        Self {
+            category: category.to_string(),
+            name: name.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DanbooruPost {
+    tag_string_artist: String,
+    tag_string_copyright: String,
+    tag_string_character: String,
+    tag_string_general: String,
+    tag_string_meta: String,
+}
+
+impl DanbooruPost {
+    fn into_tags(self) -> Vec<CategorizedTag> {
+        [
+            ("artist", self.tag_string_artist),
+            ("copyright", self.tag_string_copyright),
+            ("character", self.tag_string_character),
+            ("general", self.tag_string_general),
+            ("meta", self.tag_string_meta),
+        ]
+        .into_iter()
+        .flat_map(|(category, tags)| categorize_tag_string(category, &tags))
+        .collect()
+    }
+}
+
+#[derive(Deserialize)]
+struct GelbooruResponse {
+    #[serde(default)]
+    post: Vec<GelbooruPost>,
+}
+
+#[derive(Deserialize)]
+struct GelbooruPost {
+    tags: String,
+}
+
+#[derive(Deserialize)]
+struct GelbooruTagResponse {
+    #[serde(default)]
+    tag: Vec<GelbooruTag>,
+}
+
+#[derive(Deserialize)]
+struct GelbooruTag {
+    name: String,
+    #[serde(rename = "type")]
+    tag_type: u8,
+}
+
+impl GelbooruTag {
+    fn category(&self) -> &'static str {
+        match self.tag_type {
+            1 => "artist",
+            3 => "copyright",
+            4 => "character",
+            5 => "meta",
+            6 => "deprecated",
+            _ => "general",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MoebooruResponse {
+    posts: Vec<MoebooruPost>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct MoebooruPost {
+    tags: String,
+}
+
+#[derive(Deserialize)]
+struct E621Response {
+    post: E621Post,
+}
+
+#[derive(Deserialize)]
+struct E621Post {
+    tags: HashMap<String, Vec<String>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct BooruClient {
+    client: Client,
+    config: BooruConfig,
+}
+
+impl BooruClient {
+    pub fn new(config: BooruConfig) -> Result<Self> {
+        // e621 rejects requests without a descriptive user agent
+        let client = Client::builder()
+            .user_agent(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .build()?;
+
+        Ok(Self { client, config })
+    }
+
+    /// Returns all tags of the post with the given id
+    /// namespaced according to their category
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn tags(&self, booru: Booru, id: u64) -> Result<Vec<Tag>> {
+        let tags = match booru {
+            Booru::Danbooru => self.danbooru_tags(id).await?,
+            Booru::Gelbooru => self.gelbooru_tags(id).await?,
+            Booru::YandeRe => self.moebooru_tags("https://yande.re", id).await?,
+            Booru::Konachan => self.moebooru_tags("https://konachan.com", id).await?,
+            Booru::E621 => self.e621_tags(id).await?,
+        };
+
+        Ok(tags
+            .into_iter()
+            .filter_map(|t| to_hydrus_tag(&self.config.namespaces, t))
+            .collect())
+    }
+
+    async fn danbooru_tags(&self, id: u64) -> Result<Vec<CategorizedTag>> {
+        let post: DanbooruPost = self
+            .client
+            .get(format!("https://danbooru.donmai.us/posts/{id}.json"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(post.into_tags())
+    }
+
+    async fn gelbooru_tags(&self, id: u64) -> Result<Vec<CategorizedTag>> {
+        let response: GelbooruResponse = self
+            .gelbooru_request(&[("s", "post"), ("id", &id.to_string())])
+            .await?;
+        let Some(post) = response.post.first() else {
+            return Ok(Vec::new());
+        };
+
+        // gelbooru posts don't contain the tag categories so they need to be fetched separately
+        let response: GelbooruTagResponse = self
+            .gelbooru_request(&[("s", "tag"), ("names", &post.tags)])
+            .await?;
+
+        Ok(response
+            .tag
+            .into_iter()
+            .map(|t| CategorizedTag::new(t.category(), t.name))
+            .collect())
+    }
+
+    async fn gelbooru_request<T: for<'de> Deserialize<'de>>(
+        &self,
+        query: &[(&str, &str)],
+    ) -> Result<T> {
+        let mut request = self
+            .client
+            .get("https://gelbooru.com/index.php")
+            .query(&[("page", "dapi"), ("q", "index"), ("json", "1")])
+            .query(query);
+
+        if let Some(api_key) = &self.config.gelbooru_api_key {
+            request = request.query(&[("api_key", api_key)]);
+        }
+        if let Some(user_id) = &self.config.gelbooru_user_id {
+            request = request.query(&[("user_id", user_id)]);
+        }
+        let response = request.send().await?.error_for_status()?.json().await?;
+
+        Ok(response)
+    }
+
+    async fn moebooru_tags(&self, base_url: &str, id: u64) -> Result<Vec<CategorizedTag>> {
+        let response: MoebooruResponse = self
+            .client
+            .get(format!("{base_url}/post.json"))
+            .query(&[
+                ("api_version", "2"),
+                ("include_tags", "1"),
+                ("tags", &format!("id:{id}")),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(response.into_tags())
+    }
+
+    async fn e621_tags(&self, id: u64) -> Result<Vec<CategorizedTag>> {
+        let response: E621Response = self
+            .client
+            .get(format!("https://e621.net/posts/{id}.json"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(response
+            .post
+            .tags
+            .into_iter()
+            .flat_map(|(category, tags)| {
+                tags.into_iter()
+                    .map(move |t| CategorizedTag::new(&category, t))
+            })
+            .collect())
+    }
+}
+
+impl MoebooruResponse {
+    fn into_tags(self) -> Vec<CategorizedTag> {
+        let Some(post) = self.posts.first() else {
+            return Vec::new();
+        };
+
+        post.tags
+            .split_whitespace()
+            .map(|name| {
+                let category = self.tags.get(name).map_or("general", String::as_str);
+                CategorizedTag::new(category, name)
+            })
+            .collect()
+    }
+}
+
+fn categorize_tag_string(category: &str, tags: &str) -> Vec<CategorizedTag> {
+    tags.split_whitespace()
+        .map(|t| CategorizedTag::new(category, t))
+        .collect()
+}
+
+/// Converts the booru tag into a hydrus tag with the namespace mapped to its category.
+/// Boorus use underscores instead of spaces in tags.
+/// Returns `None` if the category is mapped to be ignored
+fn to_hydrus_tag(namespaces: &HashMap<String, String>, tag: CategorizedTag) -> Option<Tag> {
+    let name = tag.name.replace('_', " ");
+
+    match namespaces.get(&tag.category).map(String::as_str) {
+        Some("-") => None,
+        Some("") | None => Some(TagBuilder::new(name).build()),
+        Some(namespace) => Some(TagBuilder::new(name).namespace(namespace).build()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_maps_danbooru_categories_to_namespaces() {
+        let post: DanbooruPost = serde_json::from_str(
+            r#"{
+                "tag_string_artist": "some_artist",
+                "tag_string_copyright": "original",
+                "tag_string_character": "",
+                "tag_string_general": "1girl solo",
+                "tag_string_meta": "highres"
+            }"#,
+        )
+        .unwrap();
+        let namespaces = BooruConfig::default().namespaces;
+        let tags: Vec<String> = post
+            .into_tags()
+            .into_iter()
+            .filter_map(|t| to_hydrus_tag(&namespaces, t))
+            .map(|t| t.to_string())
+            .collect();
+
+        assert_eq!(
+            tags,
+            vec![
+                "creator:some artist",
+                "series:original",
+                "1girl",
+                "solo",
+                "meta:highres"
+            ]
+        );
+    }
+
+    #[test]
+    fn it_maps_moebooru_categories_to_namespaces() {
+        let response: MoebooruResponse = serde_json::from_str(
+            r#"{
+                "posts": [{ "id": 1, "tags": "some_artist long_hair" }],
+                "tags": { "some_artist": "artist", "long_hair": "general" }
+            }"#,
+        )
+        .unwrap();
+        let namespaces = BooruConfig::default().namespaces;
+        let tags: Vec<String> = response
+            .into_tags()
+            .into_iter()
+            .filter_map(|t| to_hydrus_tag(&namespaces, t))
+            .map(|t| t.to_string())
+            .collect();
+
+        assert_eq!(tags, vec!["creator:some artist", "long hair"]);
+    }
+
+    #[test]
+    fn it_drops_ignored_categories() {
+        let namespaces = BooruConfig::default().namespaces;
+        let tag = to_hydrus_tag(&namespaces, CategorizedTag::new("invalid", "tagme"));
+
+        assert!(tag.is_none());
+    }
+}
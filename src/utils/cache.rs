@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::CacheConfig;
+use crate::error::Result;
+use crate::utils::changes::SourceMetadata;
+use crate::utils::{get_cache_dir, settings_hash, to_file_name};
+use hydrus_api::wrapper::tag::Tag;
+use rustnao::Sauce;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry<T> {
+    /// Unix timestamp of when the entry was written
+    cached_at: u64,
+    value: T,
+}
+
+/// Serializable copy of the metadata retrieved from a source
+#[derive(Serialize, Deserialize)]
+struct CachedSource {
+    tags: Vec<String>,
+    #[serde(default)]
+    notes: BTreeMap<String, String>,
+}
+
+/// Serializable copy of a saucenao result
+#[derive(Serialize, Deserialize)]
+struct CachedSauce {
+    ext_urls: Vec<String>,
+    title: Option<String>,
+    site: String,
+    index: u32,
+    index_id: u32,
+    similarity: f32,
+    thumbnail: String,
+    additional_fields: Option<serde_json::Value>,
+}
+
+impl From<&Sauce> for CachedSauce {
+    fn from(s: &Sauce) -> Self {
+        Self {
+            ext_urls: s.ext_urls.clone(),
+            title: s.title.clone(),
+            site: s.site.clone(),
+            index: s.index,
+            index_id: s.index_id,
+            similarity: s.similarity,
+            thumbnail: s.thumbnail.clone(),
+            additional_fields: s.additional_fields.clone(),
+        }
+    }
+}
+
+impl From<CachedSauce> for Sauce {
+    fn from(s: CachedSauce) -> Self {
+        Self {
+            ext_urls: s.ext_urls,
+            title: s.title,
+            site: s.site,
+            index: s.index,
+            index_id: s.index_id,
+            similarity: s.similarity,
+            thumbnail: s.thumbnail,
+            additional_fields: s.additional_fields,
+        }
+    }
+}
+
+/// On-disk cache for saucenao results keyed by the file hash
+/// and for the tags retrieved from the sources keyed by their url.
+/// Keys also contain a hash of the settings the cached values depend on
+/// so that changed settings don't use entries created with the old ones
+pub struct LookupCache {
+    enabled: bool,
+    refresh: bool,
+    sauce_ttl: Duration,
+    source_ttl: Duration,
+    sauce_dir: PathBuf,
+    source_dir: PathBuf,
+    sauce_settings: String,
+    source_settings: String,
+}
+
+impl LookupCache {
+    /// Creates a new cache. If `refresh` is set all cached entries are ignored
+    /// but new results are still written to the cache
+    pub fn new(config: &CacheConfig, refresh: bool) -> Result<Self> {
+        let cache_dir = get_cache_dir()?.join("lookups");
+        let sauce_dir = cache_dir.join("sauces");
+        let source_dir = cache_dir.join("sources");
+
+        if config.enabled {
+            fs::create_dir_all(&sauce_dir)?;
+            fs::create_dir_all(&source_dir)?;
+        }
+
+        Ok(Self {
+            enabled: config.enabled,
+            refresh,
+            sauce_ttl: Duration::from_secs(config.sauce_ttl_days * SECONDS_PER_DAY),
+            source_ttl: Duration::from_secs(config.source_ttl_days * SECONDS_PER_DAY),
+            sauce_dir,
+            source_dir,
+            sauce_settings: String::new(),
+            source_settings: String::new(),
+        })
+    }
+
+    /// Sets the settings that change the results returned by saucenao
+    pub fn with_sauce_settings<T: Serialize>(mut self, settings: &T) -> Result<Self> {
+        self.sauce_settings = settings_hash(settings)?;
+
+        Ok(self)
+    }
+
+    /// Sets the settings that change the tags and notes created for a source
+    pub fn with_source_settings<T: Serialize>(mut self, settings: &T) -> Result<Self> {
+        self.source_settings = settings_hash(settings)?;
+
+        Ok(self)
+    }
+
+    /// Returns the cached saucenao results for the file with the given hash
+    pub fn sauces(&self, hash: &str) -> Option<Vec<Sauce>> {
+        let sauces: Vec<CachedSauce> = self.read(
+            self.sauce_dir.join(file_name(hash, &self.sauce_settings)),
+            self.sauce_ttl,
+        )?;
+
+        Some(sauces.into_iter().map(Sauce::from).collect())
+    }
+
+    pub fn store_sauces(&self, hash: &str, sauces: &[Sauce]) -> Result<()> {
+        let sauces: Vec<CachedSauce> = sauces.iter().map(CachedSauce::from).collect();
+
+        self.write(
+            self.sauce_dir.join(file_name(hash, &self.sauce_settings)),
+            sauces,
+        )
+    }
+
+    /// Returns the cached metadata for the source with the given url
+    pub fn source(&self, url: &str) -> Option<SourceMetadata> {
+        let source: CachedSource = self.read(
+            self.source_dir.join(file_name(url, &self.source_settings)),
+            self.source_ttl,
+        )?;
+
+        Some(SourceMetadata {
+            tags: source.tags.into_iter().map(Tag::from).collect(),
+            notes: source.notes,
+        })
+    }
+
+    pub fn store_source(&self, url: &str, metadata: &SourceMetadata) -> Result<()> {
+        let source = CachedSource {
+            tags: metadata.tags.iter().map(Tag::to_string).collect(),
+            notes: metadata.notes.clone(),
+        };
+
+        self.write(
+            self.source_dir.join(file_name(url, &self.source_settings)),
+            source,
+        )
+    }
+
+    fn read<T: DeserializeOwned>(&self, path: PathBuf, ttl: Duration) -> Option<T> {
+        if !self.enabled || self.refresh || !path.exists() {
+            return None;
+        }
+        let content = fs::read(&path).ok()?;
+        let entry: CacheEntry<T> = match serde_json::from_slice(&content) {
+            Ok(entry) => entry,
+            Err(e) => {
+                tracing::warn!("Ignoring invalid cache entry {path:?}: {e}");
+                return None;
+            }
+        };
+
+        if now() > entry.cached_at + ttl.as_secs() {
+            tracing::debug!("Cache entry {path:?} expired");
+            None
+        } else {
+            tracing::debug!("Using cache entry {path:?}");
+            Some(entry.value)
+        }
+    }
+
+    fn write<T: Serialize>(&self, path: PathBuf, value: T) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let entry = CacheEntry {
+            cached_at: now(),
+            value,
+        };
+        fs::write(path, serde_json::to_vec(&entry)?)?;
+
+        Ok(())
+    }
+}
+
+fn file_name(key: &str, settings: &str) -> String {
+    format!("{}-{settings}.json", to_file_name(key))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
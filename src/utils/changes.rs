@@ -0,0 +1,419 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::args::DryRunFormat;
+use crate::error::Result;
+use crate::utils::rules::TagRules;
+use hydrus_api::api_core::common::ServiceIdentifier;
+use hydrus_api::wrapper::builders::import_builder::FileImport;
+use hydrus_api::wrapper::builders::tag_builder::TagBuilder;
+use hydrus_api::wrapper::hydrus_file::HydrusFile;
+use hydrus_api::wrapper::tag::Tag;
+use hydrus_api::wrapper::url::Url;
+use hydrus_api::Hydrus;
+use reqwest::header::REFERER;
+use serde::Serialize;
+
+/// Changes that should be applied to a single hydrus file
+#[derive(Clone, Debug, Default)]
+pub struct FileChanges {
+    /// Tags to add mapped by the key of the tag service
+    pub tags: BTreeMap<String, Vec<Tag>>,
+    /// Urls to associate with the file
+    pub urls: Vec<String>,
+    /// Notes to set on the file mapped by their name
+    pub notes: BTreeMap<String, String>,
+}
+
+impl FileChanges {
+    pub fn add_tags<S: ToString>(&mut self, service_key: S, mut tags: Vec<Tag>) {
+        self.tags
+            .entry(service_key.to_string())
+            .or_default()
+            .append(&mut tags);
+    }
+
+    pub fn add_url<S: ToString>(&mut self, url: S) {
+        self.urls.push(url.to_string());
+    }
+
+    pub fn add_note<S1: ToString, S2: ToString>(&mut self, name: S1, note: S2) {
+        self.notes.insert(name.to_string(), note.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.values().all(Vec::is_empty) && self.urls.is_empty() && self.notes.is_empty()
+    }
+}
+
+/// Tags and notes retrieved from a single source
+#[derive(Clone, Debug, Default)]
+pub struct SourceMetadata {
+    pub tags: Vec<Tag>,
+    /// Notes mapped by their name
+    pub notes: BTreeMap<String, String>,
+}
+
+impl SourceMetadata {
+    /// Adds the value as a tag with the given namespace.
+    /// Nothing is added if the namespace or the value is empty so that
+    /// tags can be disabled in the config by setting their namespace to an empty string
+    pub fn add_namespaced<S: AsRef<str>>(&mut self, namespace: &str, value: Option<S>) {
+        let Some(value) = value else {
+            return;
+        };
+        let value = value.as_ref().trim();
+
+        if !namespace.is_empty() && !value.is_empty() {
+            let tag = TagBuilder::new(value).namespace(namespace).build();
+            self.tags.push(tag);
+        }
+    }
+
+    /// Adds the tag if it isn't empty
+    pub fn add_tag(&mut self, tag: &str) {
+        if !tag.is_empty() {
+            self.tags.push(Tag::from(tag.to_string()));
+        }
+    }
+}
+
+/// The changes recorded for a single file in dry run mode
+#[derive(Debug, Default, Serialize)]
+struct ChangeRecord {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    import_url: Option<String>,
+    tags: BTreeMap<String, Vec<String>>,
+    urls: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    notes: BTreeMap<String, String>,
+}
+
+impl ChangeRecord {
+    fn add_changes(&mut self, changes: FileChanges) {
+        for (service_key, tags) in changes.tags {
+            self.tags
+                .entry(service_key)
+                .or_default()
+                .extend(tags.iter().map(Tag::to_string));
+        }
+        self.urls.extend(changes.urls);
+        self.notes.extend(changes.notes);
+    }
+}
+
+/// The interval in which hydrus is asked for the files of pending url imports
+const IMPORT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// The time pending url imports are waited for before their urls and notes are skipped
+const IMPORT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A url queued in hydrus whose urls and notes are applied once it has been downloaded
+struct PendingImport {
+    entry: Url,
+    changes: FileChanges,
+}
+
+/// Applies all changes to hydrus or records them
+/// without touching hydrus if dry run is enabled
+pub struct HydrusWriter {
+    hydrus: Hydrus,
+    http: reqwest::Client,
+    rules: TagRules,
+    dry_run: Option<Mutex<Vec<ChangeRecord>>>,
+    pending: Mutex<Vec<PendingImport>>,
+}
+
+impl HydrusWriter {
+    pub fn new(hydrus: Hydrus, rules: TagRules, dry_run: bool) -> Self {
+        Self {
+            hydrus,
+            http: reqwest::Client::new(),
+            rules,
+            dry_run: dry_run.then(Mutex::default),
+            pending: Mutex::default(),
+        }
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run.is_some()
+    }
+
+    /// Applies the changes to an existing file
+    pub async fn apply(&self, file: &mut HydrusFile, changes: FileChanges) -> Result<()> {
+        let changes = self.apply_rules(changes);
+        self.apply_changes(file, changes).await
+    }
+
+    /// Applies the changes to an existing file without transforming the tags with the tag rules.
+    /// Used for tags that are configured as they should appear in hydrus like outcome tags
+    pub async fn apply_unchanged(&self, file: &mut HydrusFile, changes: FileChanges) -> Result<()> {
+        self.apply_changes(file, changes).await
+    }
+
+    async fn apply_changes(&self, file: &mut HydrusFile, changes: FileChanges) -> Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        if let Some(records) = &self.dry_run {
+            let hash = file.hash().await?;
+            let mut records = records.lock().unwrap();
+
+            if let Some(record) = records.iter_mut().find(|r| r.hash.as_ref() == Some(&hash)) {
+                record.add_changes(changes);
+            } else {
+                let mut record = ChangeRecord {
+                    hash: Some(hash),
+                    ..Default::default()
+                };
+                record.add_changes(changes);
+                records.push(record);
+            }
+            return Ok(());
+        }
+
+        for (service_key, tags) in changes.tags {
+            if !tags.is_empty() {
+                file.add_tags(service_key, tags).await?;
+            }
+        }
+        if !changes.urls.is_empty() {
+            file.associate_urls(changes.urls).await?;
+        }
+        if !changes.notes.is_empty() {
+            file.add_notes().add_notes(changes.notes).run().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Imports the given url with the changes.
+    /// Hydrus downloads urls in the background so the tags are sent with the import
+    /// while urls and notes are applied in [`HydrusWriter::finish`] once the files exist
+    pub async fn import_url(&self, url: &str, changes: FileChanges) -> Result<()> {
+        let changes = self.apply_rules(changes);
+
+        if let Some(records) = &self.dry_run {
+            record_import(records, url, changes);
+            return Ok(());
+        }
+        let mut import = self.hydrus.import().url(url);
+
+        for (service_key, tags) in changes.tags {
+            if !tags.is_empty() {
+                import = import.add_additional_tags(ServiceIdentifier::key(service_key), tags);
+            }
+        }
+        let entry = import.run().await?;
+        let changes = FileChanges {
+            urls: changes.urls,
+            notes: changes.notes,
+            ..Default::default()
+        };
+
+        if !changes.is_empty() {
+            self.pending
+                .lock()
+                .unwrap()
+                .push(PendingImport { entry, changes });
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the pending url imports to be downloaded by hydrus and
+    /// applies their urls and notes. Imports that don't finish in time are logged
+    pub async fn finish(&self) -> Result<()> {
+        let mut pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        let deadline = tokio::time::Instant::now() + IMPORT_TIMEOUT;
+
+        if !pending.is_empty() {
+            tracing::info!("Waiting for hydrus to import {} urls", pending.len());
+        }
+        while !pending.is_empty() {
+            let mut unfinished = Vec::new();
+
+            for mut import in pending {
+                let files = import.entry.files().await?;
+
+                if files.is_empty() {
+                    unfinished.push(import);
+                    continue;
+                }
+                for mut file in files {
+                    self.apply_changes(&mut file, import.changes.clone())
+                        .await?;
+                }
+            }
+            pending = unfinished;
+
+            if pending.is_empty() {
+                break;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                for import in &pending {
+                    tracing::warn!(
+                        "Hydrus hasn't imported {} yet. Skipped {} urls and {} notes for it",
+                        import.entry.url,
+                        import.changes.urls.len(),
+                        import.changes.notes.len()
+                    );
+                }
+                break;
+            }
+            tokio::time::sleep(IMPORT_POLL_INTERVAL).await;
+        }
+
+        Ok(())
+    }
+
+    /// Downloads the file with the given referer and imports it with the changes.
+    /// Used for hosts that refuse requests without a referer
+    /// as hydrus can't send custom headers with url imports
+    pub async fn import_download(
+        &self,
+        url: &str,
+        referer: &str,
+        mut changes: FileChanges,
+    ) -> Result<()> {
+        changes.add_url(url);
+        let changes = self.apply_rules(changes);
+
+        if let Some(records) = &self.dry_run {
+            record_import(records, url, changes);
+            return Ok(());
+        }
+        let bytes = self
+            .http
+            .get(url)
+            .header(REFERER, referer)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let mut file = self
+            .hydrus
+            .import()
+            .file(FileImport::Binary(bytes.to_vec()))
+            .run()
+            .await?;
+
+        self.apply_changes(&mut file, changes).await
+    }
+
+    /// Transforms all tags with the configured tag rules
+    fn apply_rules(&self, mut changes: FileChanges) -> FileChanges {
+        for tags in changes.tags.values_mut() {
+            *tags = self.rules.apply(std::mem::take(tags));
+        }
+
+        changes
+    }
+
+    /// Prints all changes that have been recorded in dry run mode
+    pub fn print_report(&self, format: DryRunFormat) -> Result<()> {
+        let Some(records) = &self.dry_run else {
+            return Ok(());
+        };
+        let records = records.lock().unwrap();
+
+        match format {
+            DryRunFormat::Json => println!("{}", serde_json::to_string_pretty(&*records)?),
+            DryRunFormat::Text => {
+                for record in records.iter() {
+                    print_record(record);
+                }
+                println!("{} files would be changed", records.len());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn record_import(records: &Mutex<Vec<ChangeRecord>>, url: &str, changes: FileChanges) {
+    let mut record = ChangeRecord {
+        import_url: Some(url.to_owned()),
+        ..Default::default()
+    };
+    record.add_changes(changes);
+    records.lock().unwrap().push(record);
+}
+
+fn print_record(record: &ChangeRecord) {
+    if let Some(hash) = &record.hash {
+        println!("file {hash}");
+    }
+    if let Some(url) = &record.import_url {
+        println!("import {url}");
+    }
+    for (service_key, tags) in &record.tags {
+        for tag in tags {
+            println!("  + tag [{service_key}] {tag}");
+        }
+    }
+    for url in &record.urls {
+        println!("  + url {url}");
+    }
+    for (name, note) in &record.notes {
+        println!("  + note [{name}] {}", note.replace('\n', " "));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hydrus_api::Client;
+
+    #[tokio::test]
+    async fn it_records_imports_in_dry_run_mode() {
+        let hydrus = Hydrus::new(Client::new("http://127.0.0.1:1", ""));
+        let writer = HydrusWriter::new(hydrus, TagRules::default(), true);
+        let mut changes = FileChanges::default();
+        changes.add_tags("key", vec![Tag::from("creator:someone")]);
+        changes.add_url("https://example.com/post");
+
+        writer
+            .import_url("https://example.com/image.png", changes)
+            .await
+            .unwrap();
+
+        let records = writer.dry_run.as_ref().unwrap().lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            records[0].import_url.as_deref(),
+            Some("https://example.com/image.png")
+        );
+        assert_eq!(records[0].tags["key"], vec!["creator:someone"]);
+        assert_eq!(records[0].urls, vec!["https://example.com/post"]);
+    }
+
+    #[tokio::test]
+    async fn it_records_downloads_in_dry_run_mode() {
+        let hydrus = Hydrus::new(Client::new("http://127.0.0.1:1", ""));
+        let writer = HydrusWriter::new(hydrus, TagRules::default(), true);
+        let mut changes = FileChanges::default();
+        changes.add_url("https://www.pixiv.net/artworks/100");
+
+        writer
+            .import_download(
+                "https://i.pximg.net/img-original/img/100_p0.png",
+                "https://www.pixiv.net/",
+                changes,
+            )
+            .await
+            .unwrap();
+
+        let records = writer.dry_run.as_ref().unwrap().lock().unwrap();
+        assert_eq!(
+            records[0].urls,
+            vec![
+                "https://www.pixiv.net/artworks/100",
+                "https://i.pximg.net/img-original/img/100_p0.png",
+            ]
+        );
+    }
+}
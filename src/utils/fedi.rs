@@ -1,26 +1,62 @@
 #![allow(unused)]
 use std::collections::HashMap;
+use std::sync::Mutex;
 
+use crate::config::FediConfig;
+use crate::utils::changes::SourceMetadata;
+#[cfg(test)]
+use crate::utils::fixture;
+use crate::utils::sources::{ResolvedMedia, SourceHandler};
+use crate::utils::{format_date, html_to_text};
 use crate::Result;
-use lazy_regex::regex;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hydrus_api::wrapper::builders::tag_builder::TagBuilder;
+use hydrus_api::wrapper::tag::Tag;
+use lazy_regex::{regex, regex_captures};
 use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::ClientBuilder;
 use reqwest::{redirect::Policy, StatusCode};
-use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
 use serde_json::Value;
 use std::fmt::Debug;
 
+const CONTENT_NOTE: &str = "fedi post";
+const ALT_TEXT_NOTE: &str = "alt text";
+
+/// The activitypub objects posts are served as. Lemmy uses pages while mastodon,
+/// pleroma and misskey use notes. Peertube serves videos as standalone objects
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type")]
 enum EntryData {
     Page(PostData),
+    Note(PostData),
+    Article(PostData),
+    Question(PostData),
+    Image(PostData),
+    Video(PostData),
+    Document(PostData),
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct PostData {
     id: String,
-    name: String,
+    name: Option<String>,
+    #[serde(default, deserialize_with = "one_or_many")]
     attachment: Vec<Attachment>,
+    /// The media of standalone image and video objects
+    url: Option<UrlValue>,
+    attributed_to: Option<ActorRef>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    tag: Vec<PostTag>,
+    /// The content warning of the post
+    summary: Option<String>,
+    sensitive: Option<bool>,
+    published: Option<String>,
+    content: Option<String>,
     #[serde(flatten)]
     _extra: HashMap<String, Value>,
 }
@@ -28,63 +64,546 @@ struct PostData {
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type")]
 enum Attachment {
-    Link { href: String },
+    Link {
+        href: String,
+    },
+    Document(MediaObject),
+    Image(MediaObject),
+    Video(MediaObject),
+    Audio(MediaObject),
+    /// Attachments without media like the profile fields of mastodon
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MediaObject {
+    url: UrlValue,
+    media_type: Option<String>,
+    /// The alt text of the media
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum PostTag {
+    Hashtag {
+        name: String,
+    },
+    /// Mentions and custom emojis
+    #[serde(other)]
+    Other,
+}
+
+/// The author of a post as an id or an embedded actor object
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ActorRef {
+    Id(String),
+    Object {
+        id: String,
+        #[serde(rename = "type")]
+        kind: Option<String>,
+    },
+    Many(Vec<ActorRef>),
+}
+
+impl ActorRef {
+    /// Returns the id of the author. Peertube also attributes
+    /// videos to channels so persons are preferred
+    fn actor_id(&self) -> Option<&str> {
+        match self {
+            ActorRef::Id(id) | ActorRef::Object { id, .. } => Some(id),
+            ActorRef::Many(actors) => actors
+                .iter()
+                .find(
+                    |a| matches!(a, ActorRef::Object { kind: Some(kind), .. } if kind == "Person"),
+                )
+                .or(actors.first())
+                .and_then(ActorRef::actor_id),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Actor {
+    id: String,
+    preferred_username: Option<String>,
+}
+
+/// A media file of a post with its description
+#[derive(Debug, PartialEq)]
+struct MediaAttachment {
+    url: String,
+    description: Option<String>,
+}
+
+/// Urls are either plain strings, link objects or a list of them
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum UrlValue {
+    Url(String),
+    Link(LinkObject),
+    Many(Vec<UrlValue>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LinkObject {
+    href: String,
+    media_type: Option<String>,
+    height: Option<u64>,
+}
+
+/// Properties that contain a single value or a list of values
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+fn one_or_many<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => Ok(vec![value]),
+        OneOrMany::Many(values) => Ok(values),
+    }
+}
+
+impl EntryData {
+    fn post(&self) -> &PostData {
+        match self {
+            EntryData::Page(post)
+            | EntryData::Note(post)
+            | EntryData::Article(post)
+            | EntryData::Question(post)
+            | EntryData::Image(post)
+            | EntryData::Video(post)
+            | EntryData::Document(post) => post,
+        }
+    }
+
+    /// Returns if the object is the media itself instead of a post with attachments
+    fn is_media_object(&self) -> bool {
+        matches!(
+            self,
+            EntryData::Image(_) | EntryData::Video(_) | EntryData::Document(_)
+        )
+    }
+}
+
+impl UrlValue {
+    /// Returns the url of the media with the highest resolution.
+    /// Urls without a media type are only used when there are no media urls
+    fn media_url(&self) -> Option<&str> {
+        let links = self.links();
+        let media_links = links
+            .iter()
+            .filter(|(_, media_type, _)| media_type.is_some_and(is_media_type));
+
+        media_links
+            .max_by_key(|(_, _, height)| height.unwrap_or(0))
+            .or_else(|| links.iter().find(|(_, media_type, _)| media_type.is_none()))
+            .map(|(url, _, _)| *url)
+    }
+
+    /// Returns all urls with their media type and height
+    fn links(&self) -> Vec<(&str, Option<&str>, Option<u64>)> {
+        match self {
+            UrlValue::Url(url) => vec![(url, None, None)],
+            UrlValue::Link(link) => vec![(&link.href, link.media_type.as_deref(), link.height)],
+            UrlValue::Many(urls) => urls.iter().flat_map(UrlValue::links).collect(),
+        }
+    }
+}
+
+fn is_media_type(media_type: &str) -> bool {
+    ["image/", "video/", "audio/"]
+        .iter()
+        .any(|prefix| media_type.starts_with(prefix))
+}
+
+/// Imports the attachments of fediverse posts. Urls are detected by
+/// checking once per host if it is a fediverse instance
+pub struct FediHandler {
+    config: FediConfig,
+    /// If hosts that have already been checked are fediverse instances
+    instances: Mutex<HashMap<String, bool>>,
+}
+
+impl FediHandler {
+    pub fn new(config: FediConfig) -> Self {
+        Self {
+            config,
+            instances: Mutex::default(),
+        }
+    }
+
+    async fn is_instance_url(&self, url: &str) -> bool {
+        let Some((_, host)) = regex_captures!(r#"^https?://([^/?#]+)"#, url) else {
+            return false;
+        };
+        if let Some(is_instance) = self.instances.lock().unwrap().get(host) {
+            return *is_instance;
+        }
+        let is_instance = is_fedi_instance(host).await;
+        self.instances
+            .lock()
+            .unwrap()
+            .insert(host.to_owned(), is_instance);
+
+        is_instance
+    }
+
+    /// Returns the `@user@instance` handle of the author of the post
+    async fn creator_handle(&self, post: &PostData) -> Option<String> {
+        let actor_id = post.attributed_to.as_ref()?.actor_id()?;
+
+        match get_json::<Actor>(actor_id).await {
+            Ok(actor) => actor_handle(&actor),
+            Err(e) => {
+                tracing::debug!("Failed to retrieve actor {actor_id}: {e}");
+                handle_from_actor_url(actor_id)
+            }
+        }
+    }
+}
+
+impl SourceHandler for FediHandler {
+    fn name(&self) -> &'static str {
+        "fedi"
+    }
+
+    fn matches<'a>(&'a self, url: &'a str) -> BoxFuture<'a, bool> {
+        self.is_instance_url(url).boxed()
+    }
+
+    fn resolve<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Vec<ResolvedMedia>>> {
+        async move {
+            let entry = get_post(url).await?;
+            let post = entry.post();
+            let creator = if self.config.creator_namespace.is_empty() {
+                None
+            } else {
+                self.creator_handle(post).await
+            };
+            let metadata = post_metadata(&self.config, post, creator);
+
+            Ok(post_media(&entry)
+                .into_iter()
+                .map(|media| {
+                    let mut notes = metadata.notes.clone();
+
+                    if let Some(description) =
+                        media.description.filter(|_| self.config.alt_text_note)
+                    {
+                        notes.insert(ALT_TEXT_NOTE.to_string(), description);
+                    }
+                    ResolvedMedia {
+                        tags: metadata.tags.clone(),
+                        notes,
+                        ..ResolvedMedia::new(media.url, url)
+                    }
+                })
+                .collect())
+        }
+        .boxed()
+    }
 }
 
 pub async fn is_fedi_url(url: &str) -> bool {
     get_post(url).await.is_ok()
 }
 
+/// Returns if the host publishes nodeinfo which all common
+/// fediverse servers like mastodon, misskey, lemmy and peertube do
+async fn is_fedi_instance(host: &str) -> bool {
+    let url = format!("https://{host}/.well-known/nodeinfo");
+    let response = match reqwest::get(&url).await {
+        Ok(response) if response.status().is_success() => response,
+        Ok(_) => return false,
+        Err(e) => {
+            tracing::debug!("Failed to retrieve nodeinfo of {host}: {e}");
+            return false;
+        }
+    };
+
+    response
+        .json::<Value>()
+        .await
+        .ok()
+        .and_then(|nodeinfo| nodeinfo.get("links")?.as_array().map(|l| !l.is_empty()))
+        .unwrap_or(false)
+}
+
 /// Returns all images associated with a post
 #[tracing::instrument(level = "debug")]
 pub async fn get_post_images<S: AsRef<str> + Debug>(post_url: S) -> Result<Vec<String>> {
-    let post_data = get_post(post_url.as_ref()).await?;
+    let entry = get_post(post_url.as_ref()).await?;
 
-    let urls = post_data
+    Ok(post_media(&entry).into_iter().map(|m| m.url).collect())
+}
+
+fn post_media(entry: &EntryData) -> Vec<MediaAttachment> {
+    let post_data = entry.post();
+    let mut media: Vec<MediaAttachment> = post_data
         .attachment
-        .into_iter()
-        .map(|p| {
-            let Attachment::Link { href } = p;
-            href
+        .iter()
+        .filter_map(|attachment| match attachment {
+            Attachment::Link { href } => Some(MediaAttachment {
+                url: href.to_owned(),
+                description: None,
+            }),
+            Attachment::Document(media)
+            | Attachment::Image(media)
+            | Attachment::Video(media)
+            | Attachment::Audio(media) => media.url.media_url().map(|url| MediaAttachment {
+                url: url.to_owned(),
+                description: media.name.clone().filter(|n| !n.trim().is_empty()),
+            }),
+            Attachment::Other => None,
         })
         .collect();
 
-    Ok(urls)
+    // the url of posts is the html page of the post
+    if media.is_empty() && entry.is_media_object() {
+        if let Some(url) = post_data.url.as_ref().and_then(UrlValue::media_url) {
+            media.push(MediaAttachment {
+                url: url.to_owned(),
+                description: None,
+            });
+        }
+    }
+
+    media
+}
+
+/// Creates the configured tags and notes for the post
+fn post_metadata(config: &FediConfig, post: &PostData, creator: Option<String>) -> SourceMetadata {
+    let mut metadata = SourceMetadata::default();
+    let date = post
+        .published
+        .as_deref()
+        .and_then(|published| DateTime::parse_from_rfc3339(published).ok())
+        .map(|published| format_date(published.with_timezone(&Utc)));
+    metadata.add_namespaced(&config.creator_namespace, creator);
+    metadata.add_namespaced(&config.content_warning_namespace, post.summary.as_ref());
+    metadata.add_namespaced(&config.date_namespace, date);
+
+    if config.hashtags {
+        for tag in &post.tag {
+            let PostTag::Hashtag { name } = tag else {
+                continue;
+            };
+            // hashtags are case insensitive
+            let name = name.trim_start_matches('#').to_lowercase();
+
+            if name.is_empty() {
+                continue;
+            }
+            let tag = if config.hashtag_namespace.is_empty() {
+                TagBuilder::new(&name).build()
+            } else {
+                TagBuilder::new(&name)
+                    .namespace(&config.hashtag_namespace)
+                    .build()
+            };
+            metadata.tags.push(tag);
+        }
+    }
+    if post.sensitive == Some(true) {
+        metadata.add_tag(&config.sensitive_tag);
+    }
+    if config.content_note {
+        let content = post
+            .content
+            .as_deref()
+            .map(html_to_text)
+            .unwrap_or_default();
+
+        if !content.is_empty() {
+            metadata.notes.insert(CONTENT_NOTE.to_string(), content);
+        }
+    }
+
+    metadata
+}
+
+fn actor_handle(actor: &Actor) -> Option<String> {
+    let (_, host) = regex_captures!(r#"^https?://([^/]+)"#, &actor.id)?;
+
+    match &actor.preferred_username {
+        Some(username) => Some(format!("@{username}@{host}")),
+        None => handle_from_actor_url(&actor.id),
+    }
+}
+
+/// Guesses the handle from actor urls like `/users/<name>`, `/u/<name>` or `/@<name>`
+fn handle_from_actor_url(url: &str) -> Option<String> {
+    let (_, host, username) = regex_captures!(
+        r#"^https?://([^/]+)/(?:users/|u/|accounts/|@)([\w.-]+)/?$"#,
+        url
+    )?;
+
+    Some(format!("@{username}@{host}"))
 }
 
 #[tracing::instrument(level = "debug")]
-async fn get_post(url: &str) -> Result<PostData> {
+async fn get_post(url: &str) -> Result<EntryData> {
+    get_json(url).await
+}
+
+/// Requests the activitypub object with the given id
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T> {
     let mut headers = HeaderMap::new();
     headers.insert(
         "Accept",
-        HeaderValue::from_static("application/activity+json"),
+        HeaderValue::from_static(
+            "application/activity+json, \
+            application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\"",
+        ),
     );
 
     let client = ClientBuilder::default()
         .default_headers(headers)
         .user_agent(fakeit::user_agent::random_platform())
         .build()?;
-    let mut response: EntryData = client.get(url).send().await?.json().await?;
+    Ok(client.get(url).send().await?.json().await?)
+}
+
+#[cfg(test)]
+fn media_urls(entry: &EntryData) -> Vec<String> {
+    post_media(entry).into_iter().map(|m| m.url).collect()
+}
 
-    let EntryData::Page(post) = response;
+#[test]
+fn it_creates_post_metadata() {
+    let entry = fixture::<EntryData>("fedi/mastodon_note.json");
+    let config = FediConfig {
+        date_namespace: "date".to_string(),
+        content_note: true,
+        ..Default::default()
+    };
+    let creator = handle_from_actor_url("https://mastodon.example/users/artist");
+    let metadata = post_metadata(&config, entry.post(), creator);
+    let tags: Vec<String> = metadata.tags.iter().map(|t| t.to_string()).collect();
 
-    Ok(post)
+    assert_eq!(
+        tags,
+        vec![
+            "creator:@artist@mastodon.example",
+            "content warning:eye contact",
+            "date:2023-07-01",
+            "mastoart",
+            "meta:sensitive",
+        ]
+    );
+    assert_eq!(
+        metadata.notes.get(CONTENT_NOTE).map(String::as_str),
+        Some("New drawing #MastoArt")
+    );
+    assert_eq!(
+        post_media(&entry)[0].description.as_deref(),
+        Some("A cat looking at the viewer")
+    );
+}
+
+#[test]
+fn it_resolves_actor_handles() {
+    let actor: Actor = serde_json::from_value(serde_json::json!({
+        "id": "https://misskey.example/users/9abcdef",
+        "type": "Person",
+        "preferredUsername": "sketcher"
+    }))
+    .unwrap();
+    assert_eq!(
+        actor_handle(&actor).as_deref(),
+        Some("@sketcher@misskey.example")
+    );
+
+    let entry = fixture::<EntryData>("fedi/peertube_video.json");
+    let actor_id = entry.post().attributed_to.as_ref().unwrap().actor_id();
+    assert_eq!(actor_id, Some("https://peertube.example/accounts/painter"));
+    assert_eq!(
+        handle_from_actor_url("https://peertube.example/accounts/painter").as_deref(),
+        Some("@painter@peertube.example")
+    );
+}
+
+#[test]
+fn it_checks_each_host_once() {
+    use futures::FutureExt;
+
+    let handler = FediHandler::new(FediConfig::default());
+    handler.instances.lock().unwrap().extend([
+        ("mastodon.example".to_owned(), true),
+        ("example.com".to_owned(), false),
+    ]);
+
+    let matches = |url| handler.matches(url).now_or_never();
+    assert_eq!(
+        matches("https://mastodon.example/@artist/110001"),
+        Some(true)
+    );
+    assert_eq!(matches("https://example.com/image.png"), Some(false));
+    assert_eq!(matches("not a url"), Some(false));
+}
+
+#[test]
+fn it_finds_lemmy_page_media() {
+    assert_eq!(
+        media_urls(&fixture("fedi/lemmy_page.json")),
+        vec!["https://lemmy.example/pictrs/image/5e1f0a.jpg"]
+    );
+}
+
+#[test]
+fn it_finds_mastodon_note_media() {
+    assert_eq!(
+        media_urls(&fixture("fedi/mastodon_note.json")),
+        vec![
+            "https://files.mastodon.example/media_attachments/files/110/001/original/a1.png",
+            "https://files.mastodon.example/media_attachments/files/110/002/original/b2.mp4",
+        ]
+    );
+}
+
+#[test]
+fn it_finds_misskey_note_media() {
+    assert_eq!(
+        media_urls(&fixture("fedi/misskey_note.json")),
+        vec!["https://misskey.example/files/webpublic-3c9e.webp"]
+    );
+}
+
+#[test]
+fn it_finds_the_largest_peertube_video() {
+    assert_eq!(
+        media_urls(&fixture("fedi/peertube_video.json")),
+        vec!["https://peertube.example/static/web-videos/9a1b-1080.mp4"]
+    );
 }
 
 #[tokio::test]
 async fn it_retrieves_post_data() {
-    let data = get_post("https://lemmy.blahaj.zone/post/113727")
+    let entry = get_post("https://lemmy.blahaj.zone/post/113727")
         .await
         .unwrap();
-    assert!(!data.attachment.is_empty());
+    assert!(!entry.post().attachment.is_empty());
 }
 
 #[tokio::test]
 async fn it_retrieves_post_misskey() {
-    let data = get_post("https://social.funkyfish.cool/notes/97ng0c9is3")
+    let entry = get_post("https://social.funkyfish.cool/notes/97ng0c9is3")
         .await
         .unwrap();
-    assert!(!data.attachment.is_empty());
+    assert!(!entry.post().attachment.is_empty());
 }
 
 #[tokio::test]
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use crate::error::Result;
+use crate::utils::{get_data_dir, to_file_name};
+use serde::{Deserialize, Serialize};
+
+/// The result of processing a single file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileOutcome {
+    Tagged,
+    NoSauce,
+    LowSimilarity,
+    Ambiguous,
+    Error,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry {
+    hash: String,
+    outcome: FileOutcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mime: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Records the outcome for every file processed by a job
+/// so that an interrupted job can be resumed
+pub struct Journal {
+    path: PathBuf,
+    /// The file outcomes are written to. Outcomes are only kept in memory if not set
+    file: Option<File>,
+    outcomes: HashMap<String, FileOutcome>,
+}
+
+impl Journal {
+    /// Opens the journal for the job with the given id.
+    /// Previous outcomes are only kept when resuming the job and
+    /// new outcomes are only written to disk if `persist` is set
+    pub fn open(job_id: &str, resume: bool, persist: bool) -> Result<Self> {
+        let jobs_dir = get_data_dir()?.join("jobs");
+        fs::create_dir_all(&jobs_dir)?;
+        let path = jobs_dir.join(format!("{}.jsonl", to_file_name(job_id)));
+
+        Self::open_path(path, resume, persist)
+    }
+
+    fn open_path(path: PathBuf, resume: bool, persist: bool) -> Result<Self> {
+        let outcomes = if resume && path.exists() {
+            read_outcomes(&path)?
+        } else {
+            HashMap::new()
+        };
+        let file = if persist {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(resume)
+                .write(true)
+                .truncate(!resume)
+                .open(&path)?;
+
+            if resume && !ends_with_newline(&path)? {
+                file.write_all(b"\n")?;
+            }
+            Some(file)
+        } else {
+            None
+        };
+        tracing::debug!("Using job journal {path:?}");
+
+        Ok(Self {
+            path,
+            file,
+            outcomes,
+        })
+    }
+
+    /// Returns if the file has already been processed without errors
+    pub fn is_completed(&self, hash: &str) -> bool {
+        matches!(
+            self.outcomes.get(hash),
+            Some(
+                FileOutcome::Tagged
+                    | FileOutcome::NoSauce
+                    | FileOutcome::LowSimilarity
+                    | FileOutcome::Ambiguous
+            )
+        )
+    }
+
+    /// Returns the number of files that have been processed without errors
+    pub fn completed_count(&self) -> usize {
+        self.outcomes
+            .keys()
+            .filter(|hash| self.is_completed(hash))
+            .count()
+    }
+
+    pub fn record(
+        &mut self,
+        hash: &str,
+        outcome: FileOutcome,
+        mime: Option<String>,
+        error: Option<String>,
+    ) -> Result<()> {
+        let entry = JournalEntry {
+            hash: hash.to_owned(),
+            outcome,
+            mime,
+            error,
+        };
+        if let Some(file) = &mut self.file {
+            serde_json::to_writer(&mut *file, &entry)?;
+            file.write_all(b"\n")?;
+            file.flush()?;
+        }
+        self.outcomes.insert(entry.hash, outcome);
+
+        Ok(())
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+}
+
+/// Reads the latest outcome for every file in the journal
+fn read_outcomes(path: &PathBuf) -> Result<HashMap<String, FileOutcome>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut outcomes = HashMap::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        // the last line might be incomplete if the process was killed while writing
+        match serde_json::from_str::<JournalEntry>(&line) {
+            Ok(entry) => {
+                outcomes.insert(entry.hash, entry.outcome);
+            }
+            Err(e) => tracing::warn!("Skipping invalid journal entry '{line}': {e}"),
+        }
+    }
+
+    Ok(outcomes)
+}
+
+fn ends_with_newline(path: &PathBuf) -> Result<bool> {
+    let content = fs::read(path)?;
+
+    Ok(content.last().copied().unwrap_or(b'\n') == b'\n')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    const JOURNAL: &str = concat!(
+        r#"{"hash":"a","outcome":"error","error":"timeout"}"#,
+        "\n",
+        r#"{"hash":"b","outcome":"no-sauce"}"#,
+        "\n",
+        r#"{"hash":"a","outcome":"tagged","mime":"image/png"}"#,
+        "\n",
+        r#"{"hash":"c","outc"#,
+    );
+
+    #[test]
+    fn it_reads_the_latest_outcomes() {
+        let dir = TempDir::new("journal").unwrap();
+        let path = dir.path().join("job.jsonl");
+        fs::write(&path, JOURNAL).unwrap();
+
+        let outcomes = read_outcomes(&path).unwrap();
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(outcomes["a"], FileOutcome::Tagged);
+        assert_eq!(outcomes["b"], FileOutcome::NoSauce);
+    }
+
+    #[test]
+    fn it_retries_failed_files() {
+        let dir = TempDir::new("journal").unwrap();
+        let mut journal = Journal::open_path(dir.path().join("job.jsonl"), false, false).unwrap();
+        journal
+            .record("a", FileOutcome::Ambiguous, None, None)
+            .unwrap();
+        journal
+            .record("b", FileOutcome::Error, None, Some("timeout".into()))
+            .unwrap();
+
+        assert!(journal.is_completed("a"));
+        assert!(!journal.is_completed("b"));
+        assert!(!journal.is_completed("c"));
+        assert_eq!(journal.completed_count(), 1);
+    }
+
+    #[test]
+    fn it_appends_when_resuming() {
+        let dir = TempDir::new("journal").unwrap();
+        let path = dir.path().join("job.jsonl");
+        fs::write(&path, JOURNAL).unwrap();
+
+        let mut journal = Journal::open_path(path.clone(), true, true).unwrap();
+        assert!(journal.is_completed("a"));
+        journal
+            .record("c", FileOutcome::Tagged, None, None)
+            .unwrap();
+
+        // the truncated line is terminated so that the new entry stays readable
+        let outcomes = read_outcomes(&path).unwrap();
+        assert_eq!(outcomes.len(), 3);
+        assert_eq!(outcomes["c"], FileOutcome::Tagged);
+    }
+
+    #[test]
+    fn it_starts_over_without_resuming() {
+        let dir = TempDir::new("journal").unwrap();
+        let path = dir.path().join("job.jsonl");
+        fs::write(&path, JOURNAL).unwrap();
+
+        let mut journal = Journal::open_path(path.clone(), false, true).unwrap();
+        assert!(!journal.is_completed("a"));
+        journal.record("b", FileOutcome::Error, None, None).unwrap();
+
+        let outcomes = read_outcomes(&path).unwrap();
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes["b"], FileOutcome::Error);
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashSet;
+
+use crate::args::{MatchOptions, MatchStrategy};
+use crate::utils::pixiv::pixiv_id_from_url;
+use crate::utils::saucenao::{SauceIndex, SauceMatch};
+use rustnao::Sauce;
+use serde_json::Value;
+
+/// The minimum similarity for a sauce to be used if nothing is configured
+pub const DEFAULT_MIN_SIMILARITY: f32 = 80.0;
+/// The number of top results that need to agree if nothing is configured
+const DEFAULT_AGREEMENT_COUNT: usize = 2;
+
+impl MatchOptions {
+    /// Returns the minimum similarity for sauces of the given index
+    pub fn min_similarity_for(&self, index: Option<SauceIndex>) -> f32 {
+        index
+            .and_then(|i| self.index_similarity.get(&i).copied())
+            .or(self.min_similarity)
+            .unwrap_or(DEFAULT_MIN_SIMILARITY)
+    }
+
+    /// Returns the lowest similarity a sauce of any index can be used with
+    pub fn lowest_min_similarity(&self) -> f32 {
+        self.index_similarity
+            .values()
+            .copied()
+            .fold(self.min_similarity_for(None), f32::min)
+    }
+}
+
+/// Selects the sauces that are used for a file from all of its results
+pub fn select_matches(opt: &MatchOptions, mut sauces: Vec<Sauce>) -> SauceMatch {
+    if sauces.is_empty() {
+        return SauceMatch::None;
+    }
+    sauces.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    let matches: Vec<Sauce> = sauces
+        .into_iter()
+        .filter(|s| s.similarity >= opt.min_similarity_for(SauceIndex::of_sauce(s)))
+        .collect();
+
+    if matches.is_empty() {
+        return SauceMatch::LowSimilarity;
+    }
+
+    match opt.strategy.unwrap_or_default() {
+        MatchStrategy::Best => SauceMatch::Found(matches),
+        MatchStrategy::Priority => select_by_priority(&opt.priority, matches),
+        MatchStrategy::Agreement => select_by_agreement(
+            opt.agreement_count.unwrap_or(DEFAULT_AGREEMENT_COUNT),
+            matches,
+        ),
+    }
+}
+
+/// Uses the best match of the index that comes first in the priority list.
+/// Indexes that aren't listed are only used if no listed index has a match
+fn select_by_priority(priority: &[SauceIndex], matches: Vec<Sauce>) -> SauceMatch {
+    let rank = |sauce: &Sauce| {
+        SauceIndex::of_sauce(sauce)
+            .and_then(|index| priority.iter().position(|p| *p == index))
+            .unwrap_or(priority.len())
+    };
+    // matches are ordered by similarity and the first one with the lowest rank is returned
+    let best = matches.into_iter().min_by_key(rank);
+
+    SauceMatch::Found(best.into_iter().collect())
+}
+
+/// Uses all matches pointing to the same source as the best match
+/// if the top `count` matches agree on that source.
+/// Files with fewer than `count` matches are ambiguous
+fn select_by_agreement(count: usize, matches: Vec<Sauce>) -> SauceMatch {
+    if matches.len() < count {
+        return SauceMatch::Ambiguous;
+    }
+    let source = source_ids(&matches[0]);
+    let agrees = |sauce: &Sauce| !source_ids(sauce).is_disjoint(&source);
+
+    if matches.iter().take(count).all(agrees) {
+        SauceMatch::Found(matches.into_iter().filter(agrees).collect())
+    } else {
+        SauceMatch::Ambiguous
+    }
+}
+
+/// Returns identifiers of the source the sauce points to
+/// so that results from different indexes can be compared
+fn source_ids(sauce: &Sauce) -> HashSet<String> {
+    let fields = sauce.additional_fields.as_ref();
+    let mut urls: Vec<&str> = sauce.ext_urls.iter().map(String::as_str).collect();
+    let mut ids = HashSet::new();
+
+    if let Some(source) = fields.and_then(|f| f.get("source")).and_then(Value::as_str) {
+        urls.push(source);
+    }
+    if let Some(id) = fields
+        .and_then(|f| f.get("pixiv_id"))
+        .and_then(Value::as_u64)
+    {
+        ids.insert(format!("pixiv:{id}"));
+    }
+    for url in urls {
+        if let Some(id) = pixiv_id_from_url(url) {
+            ids.insert(format!("pixiv:{id}"));
+        }
+        ids.insert(normalize_url(url));
+    }
+
+    ids
+}
+
+fn normalize_url(url: &str) -> String {
+    url.trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_start_matches("www.")
+        .trim_end_matches('/')
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rustnao::Handler;
+    use serde_json::json;
+
+    fn sauce(index: u32, similarity: f32, url: &str, fields: Value) -> Sauce {
+        Sauce {
+            ext_urls: vec![url.to_owned()],
+            title: None,
+            site: String::new(),
+            index,
+            index_id: index,
+            similarity,
+            thumbnail: String::new(),
+            additional_fields: Some(fields),
+        }
+    }
+
+    fn found_urls(result: SauceMatch) -> Vec<String> {
+        match result {
+            SauceMatch::Found(sauces) => {
+                sauces.into_iter().map(|s| s.ext_urls[0].clone()).collect()
+            }
+            _ => panic!("no sauces found"),
+        }
+    }
+
+    fn results() -> Vec<Sauce> {
+        vec![
+            sauce(
+                Handler::DANBOORU,
+                92.0,
+                "https://danbooru.donmai.us/post/show/1",
+                json!({"source": "https://www.pixiv.net/artworks/100"}),
+            ),
+            sauce(
+                Handler::PIXIV,
+                90.0,
+                "https://www.pixiv.net/member_illust.php?mode=medium&illust_id=100",
+                json!({"pixiv_id": 100}),
+            ),
+            sauce(
+                Handler::GELBOORU,
+                70.0,
+                "https://gelbooru.com/index.php?page=post&s=view&id=3",
+                json!({}),
+            ),
+        ]
+    }
+
+    #[test]
+    fn it_applies_index_thresholds() {
+        let opt = MatchOptions {
+            index_similarity: [(SauceIndex::Gelbooru, 65.0)].into(),
+            ..Default::default()
+        };
+
+        assert_eq!(found_urls(select_matches(&opt, results())).len(), 3);
+        assert_eq!(opt.lowest_min_similarity(), 65.0);
+    }
+
+    #[test]
+    fn it_prefers_trusted_indexes() {
+        let opt = MatchOptions {
+            strategy: Some(MatchStrategy::Priority),
+            priority: vec![SauceIndex::Pixiv, SauceIndex::Danbooru],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            found_urls(select_matches(&opt, results())),
+            vec!["https://www.pixiv.net/member_illust.php?mode=medium&illust_id=100"]
+        );
+    }
+
+    #[test]
+    fn it_requires_agreement() {
+        let opt = MatchOptions {
+            strategy: Some(MatchStrategy::Agreement),
+            ..Default::default()
+        };
+        assert_eq!(found_urls(select_matches(&opt, results())).len(), 2);
+
+        let mut sauces = results();
+        sauces[1].additional_fields = Some(json!({"pixiv_id": 200}));
+        sauces[1].ext_urls = vec!["https://www.pixiv.net/artworks/200".to_owned()];
+
+        assert!(matches!(
+            select_matches(&opt, sauces),
+            SauceMatch::Ambiguous
+        ));
+    }
+
+    #[test]
+    fn it_requires_enough_matches_for_agreement() {
+        let mut opt = MatchOptions {
+            strategy: Some(MatchStrategy::Agreement),
+            ..Default::default()
+        };
+        assert!(matches!(
+            select_matches(&opt, results().into_iter().take(1).collect()),
+            SauceMatch::Ambiguous
+        ));
+
+        // the gelbooru result is below the default similarity
+        opt.agreement_count = Some(3);
+        assert!(matches!(
+            select_matches(&opt, results()),
+            SauceMatch::Ambiguous
+        ));
+    }
+}
@@ -1,10 +1,24 @@
+pub mod booru;
+pub mod cache;
+pub mod changes;
 pub mod fedi;
+pub mod journal;
+pub mod matching;
 pub mod pixiv;
 pub mod reddit;
-pub mod urls;
+pub mod reddit_client;
+pub mod rules;
+pub mod saucenao;
+pub mod search;
+pub mod sources;
+pub mod thumbnails;
+pub mod twitter;
 
 use crate::error::Result;
+use chrono::{DateTime, Utc};
 use directories::ProjectDirs;
+use lazy_regex::regex;
+use serde::Serialize;
 use std::{fs, path::PathBuf};
 
 pub fn get_project_dirs() -> ProjectDirs {
@@ -22,3 +36,106 @@ pub fn get_config_dir() -> Result<PathBuf> {
 
     Ok(PathBuf::from(config_dir))
 }
+
+pub fn get_cache_dir() -> Result<PathBuf> {
+    let dirs = get_project_dirs();
+    let cache_dir = dirs.cache_dir();
+
+    if !cache_dir.exists() {
+        fs::create_dir_all(cache_dir)?;
+    }
+
+    Ok(PathBuf::from(cache_dir))
+}
+
+pub fn get_data_dir() -> Result<PathBuf> {
+    let dirs = get_project_dirs();
+    let data_dir = dirs.data_dir();
+
+    if !data_dir.exists() {
+        fs::create_dir_all(data_dir)?;
+    }
+
+    Ok(PathBuf::from(data_dir))
+}
+
+/// Converts the given key into a string that can be used as a file name
+pub fn to_file_name(key: &str) -> String {
+    key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Hashes the json representation of the settings with 64 bit FNV-1a.
+/// A fixed algorithm is used as the hash is stored in file names and has to stay
+/// the same across rust releases. Maps are converted to json values first
+/// as those are sorted by their keys
+pub fn settings_hash<T: Serialize>(settings: &T) -> Result<String> {
+    let json = serde_json::to_value(settings)?.to_string();
+    let hash = json.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+
+    Ok(format!("{hash:016x}"))
+}
+
+/// Parses the json file at the given path in the `fixtures` directory
+#[cfg(test)]
+pub fn fixture<T: serde::de::DeserializeOwned>(path: &str) -> T {
+    let path = format!("{}/fixtures/{path}", env!("CARGO_MANIFEST_DIR"));
+
+    serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+}
+
+/// Formats the date as it is added to tags, e.g. `2023-01-31`
+pub fn format_date(date: DateTime<Utc>) -> String {
+    date.format("%Y-%m-%d").to_string()
+}
+
+/// Converts html like pixiv captions or fedi posts into plain text
+pub fn html_to_text(html: &str) -> String {
+    let text = regex!(r#"(?i)<br\s*/?>"#).replace_all(html, "\n");
+    let text = regex!(r#"(?i)</p>\s*<p[^>]*>"#).replace_all(&text, "\n\n");
+    let text = regex!(r#"<[^>]*>"#).replace_all(&text, "");
+
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+        .trim()
+        .to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_converts_html_to_text() {
+        let html = r#"<p>Hello<br>there &amp; <a href="https://example.com">friends</a></p><p class="x">&lt;3 &quot;it&#39;s&quot;</p>"#;
+
+        assert_eq!(html_to_text(html), "Hello\nthere & friends\n\n<3 \"it's\"");
+    }
+
+    #[test]
+    fn it_formats_dates() {
+        let date = DateTime::parse_from_rfc3339("2023-01-31T23:30:00-02:00").unwrap();
+
+        assert_eq!(format_date(date.with_timezone(&Utc)), "2023-02-01");
+    }
+
+    #[test]
+    fn it_hashes_settings_independent_of_map_order() {
+        use std::collections::HashMap;
+
+        let a: HashMap<&str, &str> = [("artist", "creator"), ("copyright", "series")].into();
+        let b: HashMap<&str, &str> = [("copyright", "series"), ("artist", "creator")].into();
+        let c: HashMap<&str, &str> = [("artist", "artist"), ("copyright", "series")].into();
+
+        assert_eq!(settings_hash(&a).unwrap(), settings_hash(&b).unwrap());
+        assert_ne!(settings_hash(&a).unwrap(), settings_hash(&c).unwrap());
+        // the hash is part of cache file names and must not change between builds
+        assert_eq!(settings_hash(&80.0).unwrap(), "ecbeba3dd754c223");
+    }
+}
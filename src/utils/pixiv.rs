@@ -1,55 +1,414 @@
-use crate::error::Result;
-use hydrus_api::wrapper::hydrus_file::HydrusFile;
+use hydrus_api::wrapper::builders::tag_builder::TagBuilder;
 use hydrus_api::wrapper::tag::Tag;
+use lazy_regex::regex_captures;
+use pixiv_rs::responses::{Illustration, Tag as PixivTag};
 use pixiv_rs::PixivClient;
-use rustnao::{Handler, Sauce};
-use std::fs;
-use std::path::PathBuf;
-use tempdir::TempDir;
+use reqwest::header::COOKIE;
+use reqwest::Client;
+use rustnao::Sauce;
+use serde::Deserialize;
+use serde_json::Value;
 
-pub async fn get_sauces_for_file(
-    handler: &Handler,
-    tmpdir: &TempDir,
-    mut file: &mut HydrusFile,
-) -> Result<Vec<Sauce>> {
-    tracing::debug!("Creating tmp file for hydrus file {:?}", file.id);
-    let path = create_tmp_sauce_file(&tmpdir, &mut file).await?;
-    tracing::debug!("Getting sauce for hydrus file {:?}", file.id);
+use crate::args::PageOptions;
+use crate::config::PixivConfig;
+use crate::error::Error;
+use crate::utils::changes::SourceMetadata;
+use crate::utils::html_to_text;
+use crate::utils::saucenao::SauceIndex;
+use crate::utils::sources::{ResolvedMedia, SourceHandler};
+use futures::future::BoxFuture;
+use futures::FutureExt;
 
-    let sauce = handler.get_sauce(path.to_str().unwrap(), None, None)?;
-    tracing::debug!("Getting tags for hydrus file {:?}", file.id);
-    Ok(sauce)
+/// The name of the note the caption of an illustration is written to
+const CAPTION_NOTE: &str = "pixiv caption";
+/// The value of `aiType` for illustrations marked as AI generated
+const AI_GENERATED: u64 = 2;
+/// The language name used for the untranslated tag
+pub const ORIGINAL_LANGUAGE: &str = "original";
+/// The language name used for the romanized tag
+pub const ROMAJI_LANGUAGE: &str = "romaji";
+/// Images on i.pximg.net are only served with a pixiv referer
+const PIXIV_REFERER: &str = "https://www.pixiv.net/";
+
+#[derive(Deserialize)]
+struct PagesResponse {
+    error: bool,
+    message: String,
+    #[serde(default)]
+    body: Vec<Page>,
 }
 
-pub fn get_urls(sauce: &Vec<Sauce>) -> Vec<&String> {
-    sauce.iter().flat_map(|s| &s.ext_urls).collect()
+#[derive(Deserialize)]
+struct Page {
+    urls: PageUrls,
 }
 
-pub async fn get_tags_for_sauce(pixiv: &PixivClient, url: &String) -> crate::Result<Vec<Tag>> {
-    let mut tags = Vec::new();
+#[derive(Deserialize)]
+struct PageUrls {
+    original: String,
+}
+
+/// Retrieves tags, notes and pages for pixiv illustrations
+pub struct PixivTagger {
+    client: PixivClient,
+    http: Client,
+    config: PixivConfig,
+}
+
+impl PixivTagger {
+    pub fn new(config: PixivConfig) -> Self {
+        Self {
+            client: PixivClient::new(),
+            http: Client::new(),
+            config,
+        }
+    }
+
+    /// Returns the urls of the original images of all pages of the illustration.
+    /// The pages of R-18 works are only returned with a session id
+    pub async fn page_urls(&self, pixiv_id: &str) -> crate::Result<Vec<String>> {
+        // the pixiv client doesn't support retrieving pages
+        let mut request = self.http.get(format!(
+            "https://www.pixiv.net/ajax/illust/{pixiv_id}/pages"
+        ));
+        if let Some(session_id) = &self.config.session_id {
+            request = request.header(COOKIE, format!("PHPSESSID={session_id}"));
+        }
+        let response: PagesResponse = request.send().await?.json().await?;
 
-    if let Some(pixiv_id) = url.rsplit_once("=").map(|s| s.1) {
+        if response.error {
+            if self.config.session_id.is_none() {
+                return Err(Error::from(format!(
+                    "{}. The pages of R-18 works require a pixiv session_id in the config",
+                    response.message
+                )));
+            }
+            return Err(Error::from(response.message));
+        }
+
+        Ok(response.body.into_iter().map(|p| p.urls.original).collect())
+    }
+
+    pub async fn metadata_for_url(&self, url: &str) -> crate::Result<SourceMetadata> {
+        let Some(pixiv_id) = pixiv_id_from_url(url) else {
+            return Ok(SourceMetadata::default());
+        };
         tracing::trace!("Pixiv id is '{}'", pixiv_id);
-        let illustration = pixiv.illustration(pixiv_id).await?;
+        let illustration = self.client.illustration(pixiv_id).await?;
+
+        Ok(illustration_metadata(&self.config, illustration))
+    }
+}
+
+/// Maps the tags, artist, title and flags of the illustration to hydrus tags
+/// and its caption to a note
+fn illustration_metadata(config: &PixivConfig, illustration: Illustration) -> SourceMetadata {
+    let mut metadata = SourceMetadata::default();
+
+    for tag in &illustration.tags.tags {
+        metadata.tags.append(&mut translate_tag(config, tag));
+    }
+    metadata.tags.extend([
+        namespaced("creator", &illustration.user_name),
+        namespaced("pixiv artist id", &illustration.user_id),
+        namespaced("title", &illustration.title),
+        namespaced("pixiv id", &illustration.id),
+    ]);
+    if illustration.page_count > 1 {
+        metadata
+            .tags
+            .push(namespaced("page count", illustration.page_count));
+    }
+    if let Some(series) = illustration
+        .additional_fields
+        .get("seriesNavData")
+        .filter(|s| !s.is_null())
+    {
+        if let Some(title) = series.get("title").and_then(Value::as_str) {
+            metadata.tags.push(namespaced("pixiv series", title));
+        }
+        if let Some(id) = series.get("seriesId").and_then(json_to_string) {
+            metadata.tags.push(namespaced("pixiv series id", id));
+        }
+    }
+    match illustration.age_restrict {
+        1 => metadata.tags.push(namespaced("meta", "r-18")),
+        2 => metadata.tags.push(namespaced("meta", "r-18g")),
+        _ => {}
+    }
+    let ai_type = illustration
+        .additional_fields
+        .get("aiType")
+        .and_then(|t| t.as_u64());
+
+    if ai_type == Some(AI_GENERATED) {
+        metadata.tags.push(namespaced("meta", "ai generated"));
+    }
+    let caption = html_to_text(&illustration.description);
+
+    if !caption.is_empty() {
+        metadata.notes.insert(CAPTION_NOTE.to_owned(), caption);
+    }
+
+    metadata
+}
+
+/// Returns the tag in the first available language of the configured chain
+/// or in all available languages if `all_languages` is set
+fn translate_tag(config: &PixivConfig, tag: &PixivTag) -> Vec<Tag> {
+    let mut tags = Vec::new();
+
+    for language in &config.languages {
+        let value = match language.as_str() {
+            ORIGINAL_LANGUAGE => Some(&tag.tag),
+            ROMAJI_LANGUAGE => tag.romaji.as_ref(),
+            language => tag.translation.get(language),
+        };
+        let Some(value) = value else {
+            continue;
+        };
+        let tag = match config.language_namespaces.get(language) {
+            Some(namespace) => namespaced(namespace, value),
+            None => TagBuilder::new(value).build(),
+        };
+        tags.push(tag);
+
+        if !config.all_languages {
+            break;
+        }
+    }
+
+    tags
+}
 
-        for tag in illustration.tags.tags {
-            let tag_value = tag.translation.get("en").unwrap_or(&tag.tag);
-            tags.push(Tag::from(tag_value));
+/// Imports pixiv works, either by their work url or with every page separately
+pub struct PixivHandler {
+    pixiv: PixivTagger,
+    pages: PageOptions,
+}
+
+impl PixivHandler {
+    pub fn new(pixiv: PixivTagger, pages: PageOptions) -> Self {
+        Self { pixiv, pages }
+    }
+}
+
+impl SourceHandler for PixivHandler {
+    fn name(&self) -> &'static str {
+        "pixiv"
+    }
+
+    fn matches<'a>(&'a self, url: &'a str) -> BoxFuture<'a, bool> {
+        futures::future::ready(pixiv_id_from_url(url).is_some()).boxed()
+    }
+
+    fn resolve<'a>(&'a self, url: &'a str) -> BoxFuture<'a, crate::Result<Vec<ResolvedMedia>>> {
+        async move {
+            match pixiv_id_from_url(url) {
+                Some(pixiv_id) if self.pages.all_pages => {
+                    page_media(&self.pixiv, pixiv_id, self.pages.page_tag).await
+                }
+                _ => Ok(vec![ResolvedMedia {
+                    url: url.to_owned(),
+                    ..Default::default()
+                }]),
+            }
         }
+        .boxed()
     }
+}
 
-    Ok(tags)
+/// Returns every page of the pixiv work as separate media
+/// that is associated with the work url.
+/// The pages are downloaded with a pixiv referer as i.pximg.net refuses requests without one
+pub async fn page_media(
+    pixiv: &PixivTagger,
+    pixiv_id: &str,
+    add_page_tag: bool,
+) -> crate::Result<Vec<ResolvedMedia>> {
+    let work_url = format!("https://www.pixiv.net/artworks/{pixiv_id}");
+    let page_urls = pixiv.page_urls(pixiv_id).await?;
+
+    let media = (1..)
+        .zip(page_urls)
+        .map(|(page, url)| {
+            let mut media = ResolvedMedia::new(url, &work_url);
+            media.referer = Some(PIXIV_REFERER.to_owned());
+
+            if add_page_tag {
+                media.tags.push(page_tag(page));
+            }
+            media
+        })
+        .collect();
+
+    Ok(media)
+}
+
+/// Returns the `page:` tag for the given page number
+pub fn page_tag(page: u32) -> Tag {
+    namespaced("page", page)
 }
 
-async fn create_tmp_sauce_file(tmpdir: &TempDir, file: &mut HydrusFile) -> crate::Result<PathBuf> {
-    let hash = file.hash().await?;
-    let bytes = file.retrieve().await?.bytes;
-    let path = tmpdir.path().join(&hash);
-    fs::write(&path, bytes)?;
+fn json_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.to_owned()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
 
-    Ok(path)
+fn namespaced<S: ToString>(namespace: &str, name: S) -> Tag {
+    TagBuilder::new(name).namespace(namespace).build()
 }
 
-pub fn get_pixiv_url(sauce: &Vec<Sauce>) -> Option<&String> {
-    sauce.first().and_then(|s| s.ext_urls.first())
+/// Returns the url of the artwork page the sauce was found on
+pub fn get_pixiv_url(sauce: &Sauce) -> Option<&String> {
+    sauce
+        .ext_urls
+        .iter()
+        .find(|url| pixiv_id_from_url(url).is_some())
+}
+
+/// Returns the page number of the image the sauce was found for.
+/// Saucenao thumbnails of multi page works contain the page index
+pub fn page_of_sauce(sauce: &Sauce) -> Option<u32> {
+    if SauceIndex::of_sauce(sauce) != Some(SauceIndex::Pixiv) {
+        return None;
+    }
+    let (_, index) = regex_captures!(r#"\d+_p(\d+)"#, &sauce.thumbnail)?;
+
+    index.parse::<u32>().ok().map(|i| i + 1)
+}
+
+/// Extracts the illustration id from pixiv artwork urls
+pub fn pixiv_id_from_url(url: &str) -> Option<&str> {
+    let (_, _, id) = regex_captures!(r#"pixiv\.net/.*(illust_id=|artworks/)(\d+)"#, url)?;
+
+    Some(id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn illustration() -> Illustration {
+        serde_json::from_value(serde_json::json!({
+            "id": "100",
+            "title": "Sunset",
+            "description": "First line<br />Second &amp; <a href=\"https://example.com\">last</a> line",
+            "illustType": 0,
+            "createDate": "2023-01-01T00:00:00+00:00",
+            "uploadDate": "2023-01-01T00:00:00+00:00",
+            "xRestrict": 1,
+            "urls": {"mini": "", "thumb": "", "small": "", "regular": "", "original": ""},
+            "tags": {"authorId": "42", "tags": [
+                {"tag": "風景", "translation": {"en": "landscape"}},
+                {"tag": "オリジナル"}
+            ]},
+            "alt": "",
+            "userId": "42",
+            "userName": "Artist",
+            "userAccount": "artist",
+            "width": 100,
+            "height": 100,
+            "pageCount": 3,
+            "bookmarkCount": 0,
+            "likeCount": 0,
+            "commentCount": 0,
+            "responseCount": 0,
+            "viewCount": 0,
+            "aiType": 2,
+            "seriesNavData": {"seriesId": 7, "title": "Seasons", "order": 2}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn it_maps_illustration_metadata() {
+        let metadata = illustration_metadata(&PixivConfig::default(), illustration());
+        let tags: Vec<String> = metadata.tags.iter().map(Tag::to_string).collect();
+
+        assert_eq!(
+            tags,
+            vec![
+                "landscape",
+                "オリジナル",
+                "creator:Artist",
+                "pixiv artist id:42",
+                "title:Sunset",
+                "pixiv id:100",
+                "page count:3",
+                "pixiv series:Seasons",
+                "pixiv series id:7",
+                "meta:r-18",
+                "meta:ai generated",
+            ]
+        );
+        assert_eq!(
+            metadata.notes[CAPTION_NOTE],
+            "First line\nSecond & last line"
+        );
+    }
+
+    #[test]
+    fn it_adds_tags_in_all_configured_languages() {
+        let config = PixivConfig {
+            languages: vec![
+                "en".into(),
+                ROMAJI_LANGUAGE.into(),
+                ORIGINAL_LANGUAGE.into(),
+            ],
+            all_languages: true,
+            language_namespaces: [(ORIGINAL_LANGUAGE.to_owned(), "ja".to_owned())].into(),
+            ..Default::default()
+        };
+        let tag: PixivTag = serde_json::from_value(serde_json::json!({
+            "tag": "風景",
+            "romaji": "fuukei",
+            "translation": {"en": "landscape"}
+        }))
+        .unwrap();
+        let tags: Vec<String> = translate_tag(&config, &tag)
+            .iter()
+            .map(Tag::to_string)
+            .collect();
+
+        assert_eq!(tags, vec!["landscape", "fuukei", "ja:風景"]);
+    }
+
+    #[test]
+    fn it_extracts_pixiv_ids() {
+        assert_eq!(
+            pixiv_id_from_url("https://www.pixiv.net/member_illust.php?mode=medium&illust_id=123"),
+            Some("123")
+        );
+        assert_eq!(
+            pixiv_id_from_url("https://www.pixiv.net/en/artworks/456"),
+            Some("456")
+        );
+        assert_eq!(
+            pixiv_id_from_url("https://danbooru.donmai.us/posts/1"),
+            None
+        );
+    }
+
+    #[test]
+    fn it_finds_the_page_of_sauces() {
+        let mut sauce = Sauce {
+            ext_urls: Vec::new(),
+            title: None,
+            site: String::new(),
+            index: 5,
+            index_id: 5,
+            similarity: 90.0,
+            thumbnail: "https://img1.saucenao.com/res/pixiv/1234/manga/12345678_p2.jpg?auth=x"
+                .into(),
+            additional_fields: None,
+        };
+        assert_eq!(page_of_sauce(&sauce), Some(3));
+
+        sauce.thumbnail = "https://img1.saucenao.com/res/pixiv/1234/12345678.jpg".into();
+        assert_eq!(page_of_sauce(&sauce), None);
+    }
 }
@@ -1,14 +1,31 @@
 #![allow(unused)]
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
+use crate::args::{ListingSort, ListingTime};
+use crate::config::RedditConfig;
+use crate::error::RedditError;
+use crate::utils::changes::SourceMetadata;
+use crate::utils::format_date;
+use crate::utils::reddit_client::RedditClient;
+use crate::utils::sources::{ResolvedMedia, SourceHandler};
 use crate::Result;
-use lazy_regex::regex;
+use chrono::{TimeZone, Utc};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hydrus_api::wrapper::builders::tag_builder::TagBuilder;
+use hydrus_api::wrapper::tag::Tag;
+use lazy_regex::{regex, regex_captures};
 use reqwest::ClientBuilder;
-use reqwest::{redirect::Policy, StatusCode};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde_json::Value;
 use std::fmt::Debug;
 
+const SELFTEXT_NOTE: &str = "reddit selftext";
+/// The maximum number of posts reddit returns per listing page
+const LISTING_PAGE_SIZE: usize = 100;
+
 #[derive(Deserialize)]
 #[serde(tag = "kind", content = "data")]
 enum DataEntry {
@@ -18,6 +35,8 @@ enum DataEntry {
 #[derive(Deserialize)]
 struct ListingEntry {
     children: Vec<DataEntryChild>,
+    /// The id of the last entry used to request the next page
+    after: Option<String>,
     #[serde(flatten)]
     _extra: HashMap<String, Value>,
 }
@@ -26,9 +45,9 @@ struct ListingEntry {
 #[serde(tag = "kind", content = "data")]
 enum DataEntryChild {
     #[serde(alias = "t3")]
-    T3(T3Data),
+    T3(Box<T3Data>),
     #[serde(alias = "t1")]
-    T1(HashMap<String, Value>),
+    T1(T1Data),
     #[serde(alias = "more")]
     More(HashMap<String, Value>),
 }
@@ -38,10 +57,46 @@ struct T3Data {
     id: String,
     url: Option<String>,
     gallery_data: Option<GalleryData>,
+    media_metadata: Option<HashMap<String, MediaMetadata>>,
+    media: Option<PostMedia>,
+    secure_media: Option<PostMedia>,
+    /// The original post of crossposts
+    #[serde(default)]
+    crosspost_parent_list: Vec<T3Data>,
+    subreddit: Option<String>,
+    author: Option<String>,
+    title: Option<String>,
+    link_flair_text: Option<String>,
+    #[serde(default)]
+    over_18: bool,
+    created_utc: Option<f64>,
+    #[serde(default)]
+    selftext: String,
+    permalink: Option<String>,
+    #[serde(default)]
+    is_self: bool,
+    /// Set when the post was removed by moderators, reddit or its author
+    removed_by_category: Option<String>,
     #[serde(flatten)]
     _extra: HashMap<String, Value>,
 }
 
+/// A comment
+#[derive(Deserialize, Debug)]
+struct T1Data {
+    id: String,
+    /// The fullname (`t3_<id>`) of the post the comment belongs to
+    link_id: Option<String>,
+}
+
+/// The post or comment found for a url
+#[derive(Debug)]
+enum PostEntry {
+    Post(Box<T3Data>),
+    /// A comment with the id of the post it belongs to
+    Comment(String),
+}
+
 #[derive(Deserialize, Debug)]
 struct GalleryData {
     items: Vec<GalleryItem>,
@@ -53,64 +108,625 @@ struct GalleryItem {
     id: u64,
 }
 
-/// Returns all images associated with a post
-#[tracing::instrument(level = "debug")]
-pub async fn get_post_images<S: AsRef<str> + Debug>(post_url: S) -> Result<Vec<String>> {
-    let post_data = get_post(post_url.as_ref()).await?;
+/// Information about a media item of a gallery post
+#[derive(Deserialize, Debug)]
+struct MediaMetadata {
+    status: String,
+    /// The kind of media, e.g. `Image` or `AnimatedImage`
+    e: Option<String>,
+    /// The mime type of the media
+    m: Option<String>,
+    /// The source in its original resolution
+    s: Option<MediaSource>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MediaSource {
+    u: Option<String>,
+    gif: Option<String>,
+    mp4: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PostMedia {
+    reddit_video: Option<RedditVideo>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RedditVideo {
+    /// A mp4 of the video without audio
+    fallback_url: String,
+    has_audio: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ImgurResponse<T> {
+    data: T,
+}
+
+#[derive(Deserialize)]
+struct ImgurImage {
+    link: String,
+    mp4: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RedgifsToken {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct RedgifsResponse {
+    gif: RedgifsGif,
+}
+
+#[derive(Deserialize)]
+struct RedgifsGif {
+    urls: RedgifsUrls,
+}
+
+#[derive(Deserialize)]
+struct RedgifsUrls {
+    hd: Option<String>,
+    sd: Option<String>,
+}
+
+/// A page of posts of a listing
+pub struct ListingPage {
+    /// The media of each post with media on the page
+    pub posts: Vec<Vec<ResolvedMedia>>,
+    /// The total number of posts on the page including posts without media
+    pub post_count: usize,
+    /// The id used to request the next page. Empty on the last page
+    pub after: Option<String>,
+}
+
+/// A listing of posts like a subreddit, a multireddit or the posts of a user
+#[derive(Clone, Debug)]
+pub struct RedditListing {
+    path: String,
+    kind: ListingKind,
+    sort: ListingSort,
+    time: Option<ListingTime>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ListingKind {
+    Subreddit,
+    Multireddit,
+    User,
+    Saved,
+}
+
+impl RedditListing {
+    /// Parses listings like `r/pics`, `u/someone`, `u/someone/m/multi`,
+    /// `u/someone/saved` or the url of one of them
+    pub fn parse(listing: &str, sort: ListingSort, time: Option<ListingTime>) -> Result<Self> {
+        let path = regex!(r#"^(https?://)?((www|old|new)\.)?(reddit\.com)?/?"#i)
+            .replace(listing.trim(), "")
+            .trim_end_matches('/')
+            .to_owned();
+        let (kind, path) = if let Some((_, name)) = regex_captures!(r#"^r/(\w+)$"#i, &path) {
+            (ListingKind::Subreddit, format!("r/{name}"))
+        } else if let Some((_, user, multi)) =
+            regex_captures!(r#"^(?:u|user)/([\w-]+)/m/(\w+)$"#i, &path)
+        {
+            (ListingKind::Multireddit, format!("user/{user}/m/{multi}"))
+        } else if let Some((_, user)) = regex_captures!(r#"^(?:u|user)/([\w-]+)/saved$"#i, &path) {
+            (ListingKind::Saved, format!("user/{user}/saved"))
+        } else if let Some((_, user)) =
+            regex_captures!(r#"^(?:u|user)/([\w-]+)(?:/submitted)?$"#i, &path)
+        {
+            (ListingKind::User, format!("user/{user}/submitted"))
+        } else {
+            return Err(format!("Invalid reddit listing '{listing}'").into());
+        };
+
+        Ok(Self {
+            path,
+            kind,
+            sort,
+            time,
+        })
+    }
+
+    /// Returns the url of the page after the given post id with at most `limit` posts
+    fn page_url(&self, after: Option<&str>, limit: Option<usize>) -> String {
+        let sort = self.sort.as_str();
+        let limit = limit.map_or(LISTING_PAGE_SIZE, |l| l.min(LISTING_PAGE_SIZE));
+        let mut url = match self.kind {
+            // user listings take the sort order as a parameter
+            ListingKind::User | ListingKind::Saved => format!(
+                "https://www.reddit.com/{}.json?sort={sort}&limit={limit}",
+                self.path
+            ),
+            ListingKind::Subreddit | ListingKind::Multireddit => format!(
+                "https://www.reddit.com/{}/{sort}.json?limit={limit}",
+                self.path
+            ),
+        };
+        if let Some(time) = self.time {
+            url.push_str(&format!("&t={}", time.as_str()));
+        }
+        if let Some(after) = after {
+            url.push_str(&format!("&after={after}"));
+        }
+
+        url
+    }
+}
+
+/// Imports the images of reddit posts with tags for the post metadata
+pub struct RedditHandler {
+    config: RedditConfig,
+    client: RedditClient,
+}
+
+impl RedditHandler {
+    pub fn new(config: RedditConfig) -> Result<Self> {
+        let client = RedditClient::new(&config)?;
+
+        Ok(Self { config, client })
+    }
 
+    /// Returns all images associated with a post
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn post_images(&self, post_url: &str) -> Result<Vec<String>> {
+        let post_data = self.get_post(post_url).await?;
+
+        Ok(self
+            .resolve_hosts(post_images(&self.config, post_data))
+            .await)
+    }
+
+    /// Returns the media of all posts on the page of the listing after the given post id.
+    /// Pages contain at most `limit` posts so that no more posts are resolved than needed
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn listing_page(
+        &self,
+        listing: &RedditListing,
+        after: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<ListingPage> {
+        // requests are only authenticated when a client id is set
+        let authenticated = self.config.client_id.is_some() && self.config.refresh_token.is_some();
+
+        if listing.kind == ListingKind::Saved && !authenticated {
+            return Err("Saved posts can only be imported with a reddit client_id \
+                and refresh_token in the config"
+                .into());
+        }
+        let url = listing.page_url(after, limit);
+        let entry: DataEntry = self.client.get_json(&url).await?;
+        let DataEntry::Listing(entry) = entry;
+        let post_count = entry.children.len();
+        let mut posts = Vec::new();
+
+        for child in entry.children {
+            // saved listings also contain comments
+            let DataEntryChild::T3(post) = child else {
+                continue;
+            };
+            if post.is_self {
+                tracing::debug!("Skipping self post {}", post.id);
+                continue;
+            }
+            let Some(permalink) = &post.permalink else {
+                continue;
+            };
+            let post_url = format!("https://www.reddit.com{permalink}");
+
+            if let Err(e) = check_removed(&post_url, &post) {
+                tracing::debug!("Skipping post {}: {e}", post.id);
+                continue;
+            }
+            posts.push(self.post_media(*post, &post_url).await);
+        }
+
+        Ok(ListingPage {
+            posts,
+            post_count,
+            after: entry.after,
+        })
+    }
+
+    async fn post_media(&self, post: T3Data, post_url: &str) -> Vec<ResolvedMedia> {
+        let metadata = post_metadata(&self.config, &post);
+        let images = self.resolve_hosts(post_images(&self.config, post)).await;
+
+        images
+            .into_iter()
+            .map(|image| ResolvedMedia {
+                tags: metadata.tags.clone(),
+                notes: metadata.notes.clone(),
+                ..ResolvedMedia::new(image, post_url)
+            })
+            .collect()
+    }
+
+    /// Replaces urls of external hosts that hydrus can't download with urls of the media files.
+    /// Urls that fail to resolve are returned unchanged
+    async fn resolve_hosts(&self, urls: Vec<String>) -> Vec<String> {
+        let mut resolved = Vec::new();
+
+        for url in urls {
+            match self.resolve_host(&url).await {
+                Ok(urls) => resolved.extend(urls),
+                Err(e) => {
+                    tracing::warn!("Failed to resolve media of {url}: {e}");
+                    resolved.push(url);
+                }
+            }
+        }
+
+        resolved
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    async fn resolve_host(&self, url: &str) -> Result<Vec<String>> {
+        if is_reddit_video_url(url) {
+            // v.redd.it links redirect to the post the video was uploaded in
+            let post = self.get_post(url).await?;
+            let video = reddit_video(&post).ok_or("No video found for v.redd.it url")?;
+
+            return Ok(vec![video_url(&self.config, &post, video)]);
+        }
+        if let Some(url) = imgur_gifv_to_mp4(url) {
+            return Ok(vec![url]);
+        }
+        if let Some(imgur_url) = ImgurUrl::parse(url) {
+            let Some(client_id) = &self.config.imgur_client_id else {
+                tracing::debug!("No imgur client id configured");
+                return Ok(vec![url.to_owned()]);
+            };
+            return imgur_media(client_id, imgur_url).await;
+        }
+        if let Some(id) = redgifs_id_from_url(url) {
+            return Ok(vec![redgifs_media(&id).await?]);
+        }
+
+        Ok(vec![url.to_owned()])
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    async fn get_post(&self, url: &str) -> Result<T3Data> {
+        let url = resolve_redirects(&self.client, url).await?;
+        let url = post_url_of_comment(&url).unwrap_or(url);
+
+        let post = match self.get_post_entry(&url).await? {
+            PostEntry::Post(post) => post,
+            PostEntry::Comment(post_id) => {
+                tracing::debug!("Resolving comment {url} to its post {post_id}");
+                let post_url = format!("https://www.reddit.com/comments/{post_id}/");
+
+                match self.get_post_entry(&post_url).await? {
+                    PostEntry::Post(post) => post,
+                    PostEntry::Comment(_) => return Err(RedditError::CommentLink(url).into()),
+                }
+            }
+        };
+        check_removed(&url, &post)?;
+
+        Ok(*post)
+    }
+
+    async fn get_post_entry(&self, url: &str) -> Result<PostEntry> {
+        let mut url = url.to_string();
+
+        // url cleanup
+        // add trailing slash and remove path params
+        if let Some((left, _)) = url.rsplit_once('?') {
+            url = left.to_string();
+        }
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        let response: Vec<DataEntry> = self.client.get_json(&format!("{}.json", url)).await?;
+
+        post_from_response(&url, response)
+    }
+}
+
+impl SourceHandler for RedditHandler {
+    fn name(&self) -> &'static str {
+        "reddit"
+    }
+
+    fn matches<'a>(&'a self, url: &'a str) -> BoxFuture<'a, bool> {
+        futures::future::ready(is_reddit_url(url)).boxed()
+    }
+
+    fn resolve<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Vec<ResolvedMedia>>> {
+        async move {
+            let post = self.get_post(url).await?;
+
+            Ok(self.post_media(post, url).await)
+        }
+        .boxed()
+    }
+}
+
+pub fn is_reddit_url(url: &str) -> bool {
+    let r = regex!(r#"^http(s)?://(www\.|v\.)?(reddit\.com|redd\.it|reddit\.app\.link).*$"#i);
+    r.is_match(url)
+}
+
+fn post_images(config: &RedditConfig, mut post_data: T3Data) -> Vec<String> {
+    if let Some(parent) = post_data.crosspost_parent_list.pop() {
+        return post_images(config, parent);
+    }
+    if let Some(video) = reddit_video(&post_data) {
+        return vec![video_url(config, &post_data, video)];
+    }
     if let Some(gallery_data) = post_data.gallery_data {
-        let urls = gallery_data
+        let media_metadata = post_data.media_metadata.unwrap_or_default();
+
+        gallery_data
             .items
             .into_iter()
-            .map(|item| item.media_id)
-            .map(|media_id| format!("https://i.redd.it/{}.jpg", media_id))
-            .collect();
-        Ok(urls)
+            .filter_map(|item| gallery_item_url(&item.media_id, media_metadata.get(&item.media_id)))
+            .collect()
     } else if let Some(url) = post_data.url {
-        Ok(vec![url])
+        vec![url]
     } else {
-        Ok(Vec::new())
+        Vec::new()
     }
 }
 
-#[tracing::instrument(level = "debug")]
-async fn get_post(url: &str) -> Result<T3Data> {
-    let mut url = resolve_redirects(url).await?;
+fn reddit_video(post_data: &T3Data) -> Option<&RedditVideo> {
+    post_data
+        .secure_media
+        .as_ref()
+        .or(post_data.media.as_ref())
+        .and_then(|media| media.reddit_video.as_ref())
+}
 
-    // url cleanup
-    // add trailing slash and remove path params
-    if let Some((left, right)) = url.rsplit_once('?') {
-        url = left.to_string();
+/// Returns the url a reddit video is imported from. Reddit serves the audio of videos
+/// as a separate DASH track, so the fallback mp4 of videos with audio is silent.
+/// Those are imported with the post url instead if configured
+fn video_url(config: &RedditConfig, post_data: &T3Data, video: &RedditVideo) -> String {
+    let fallback_url = unescape_url(&video.fallback_url);
+
+    if video.has_audio == Some(false) {
+        return fallback_url;
+    }
+    match &post_data.permalink {
+        Some(permalink) if config.video_post_url => format!("https://www.reddit.com{permalink}"),
+        _ => {
+            tracing::warn!("Importing video {fallback_url} without its audio track");
+            fallback_url
+        }
     }
-    if !url.ends_with('/') {
-        url.push('/');
+}
+
+fn is_reddit_video_url(url: &str) -> bool {
+    regex!(r#"^https?://v\.redd\.it/\w+/?$"#i).is_match(url)
+}
+
+/// Direct links to `.gifv` files point to a html page that embeds the mp4
+fn imgur_gifv_to_mp4(url: &str) -> Option<String> {
+    let (_, id) = regex_captures!(r#"^https?://i\.imgur\.com/(\w+)\.gifv$"#i, url)?;
+
+    Some(format!("https://i.imgur.com/{id}.mp4"))
+}
+
+#[derive(Debug, PartialEq)]
+enum ImgurUrl {
+    Album(String),
+    Image(String),
+}
+
+impl ImgurUrl {
+    fn parse(url: &str) -> Option<Self> {
+        // newer gallery urls prefix the id with the title, e.g. `/gallery/some-title-AbC12`
+        if let Some((_, id)) = regex_captures!(
+            r#"^https?://(?:www\.|m\.)?imgur\.com/(?:a|gallery)/(?:[\w-]*-)?(\w+)/?$"#i,
+            url
+        ) {
+            Some(Self::Album(id.to_owned()))
+        } else {
+            regex_captures!(r#"^https?://(?:www\.|m\.)?imgur\.com/(\w+)/?$"#i, url)
+                .map(|(_, id)| Self::Image(id.to_owned()))
+        }
     }
-    let client = ClientBuilder::default()
-        .user_agent(fakeit::user_agent::random_platform())
-        .build()?;
-    let mut response: Vec<DataEntry> = client
-        .get(format!("{}.json", url))
+}
+
+/// Returns the media of an imgur album or image page
+async fn imgur_media(client_id: &str, imgur_url: ImgurUrl) -> Result<Vec<String>> {
+    let client = reqwest::Client::new();
+    let request = match &imgur_url {
+        ImgurUrl::Album(id) => format!("https://api.imgur.com/3/album/{id}/images"),
+        ImgurUrl::Image(id) => format!("https://api.imgur.com/3/image/{id}"),
+    };
+    let request = client
+        .get(request)
+        .header("Authorization", format!("Client-ID {client_id}"));
+
+    let images = match imgur_url {
+        ImgurUrl::Album(_) => {
+            request
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<ImgurResponse<Vec<ImgurImage>>>()
+                .await?
+                .data
+        }
+        ImgurUrl::Image(_) => vec![
+            request
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<ImgurResponse<ImgurImage>>()
+                .await?
+                .data,
+        ],
+    };
+
+    Ok(images
+        .into_iter()
+        .map(|image| image.mp4.unwrap_or(image.link))
+        .collect())
+}
+
+/// Extracts the id of redgifs urls. Gfycat ids are used by redgifs as well
+fn redgifs_id_from_url(url: &str) -> Option<String> {
+    let (_, id) = regex_captures!(
+        r#"^https?://(?:www\.|v3\.)?(?:redgifs\.com/(?:watch|ifr)|gfycat\.com(?:/\w+)*)/([a-z]+)(?:[-?#].*)?$"#i,
+        url
+    )?;
+
+    Some(id.to_lowercase())
+}
+
+/// Returns the url of the video file of a redgifs post
+async fn redgifs_media(id: &str) -> Result<String> {
+    let client = reqwest::Client::new();
+    let token: RedgifsToken = client
+        .get("https://api.redgifs.com/v2/auth/temporary")
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let response: RedgifsResponse = client
+        .get(format!("https://api.redgifs.com/v2/gifs/{id}"))
+        .bearer_auth(token.token)
         .send()
         .await?
+        .error_for_status()?
         .json()
         .await?;
-    response.reverse();
-    let first_entry = response.pop().unwrap();
-    let mut first_listing = match first_entry {
-        DataEntry::Listing(l) => l.children,
+    let urls = response.gif.urls;
+
+    urls.hd
+        .or(urls.sd)
+        .ok_or_else(|| format!("No video found for redgifs id {id}").into())
+}
+
+/// Returns the url of a gallery item based on its media type.
+/// Items that failed to process on reddit's side are skipped
+fn gallery_item_url(media_id: &str, metadata: Option<&MediaMetadata>) -> Option<String> {
+    let Some(metadata) = metadata else {
+        return Some(format!("https://i.redd.it/{media_id}.jpg"));
+    };
+    if metadata.status != "valid" {
+        tracing::warn!(
+            "Skipping gallery item {media_id} with status {}",
+            metadata.status
+        );
+        return None;
+    }
+    let source = metadata.s.as_ref();
+
+    if metadata.e.as_deref() == Some("AnimatedImage") {
+        // the gif is the original upload while the mp4 is a preview converted by reddit
+        let animated_url = source.and_then(|s| s.gif.as_ref().or(s.mp4.as_ref()));
+
+        if let Some(url) = animated_url {
+            return Some(unescape_url(url));
+        }
+    }
+    let extension = metadata
+        .m
+        .as_deref()
+        .and_then(|mime| mime.strip_prefix("image/"))
+        .map(|ext| if ext == "jpeg" { "jpg" } else { ext });
+
+    match (extension, source.and_then(|s| s.u.as_ref())) {
+        (Some(extension), _) => Some(format!("https://i.redd.it/{media_id}.{extension}")),
+        (None, Some(url)) => Some(unescape_url(url)),
+        (None, None) => Some(format!("https://i.redd.it/{media_id}.jpg")),
+    }
+}
+
+/// Urls in the reddit json api are html escaped
+fn unescape_url(url: &str) -> String {
+    url.replace("&amp;", "&")
+}
+
+/// Creates the configured tags and notes for the post
+fn post_metadata(config: &RedditConfig, post: &T3Data) -> SourceMetadata {
+    let mut metadata = SourceMetadata::default();
+    let author = post
+        .author
+        .as_ref()
+        .filter(|author| author.as_str() != "[deleted]");
+    let date = post
+        .created_utc
+        .and_then(|created| Utc.timestamp_opt(created as i64, 0).single())
+        .map(format_date);
+    metadata.add_namespaced(&config.subreddit_namespace, post.subreddit.as_ref());
+    metadata.add_namespaced(&config.creator_namespace, author);
+    metadata.add_namespaced(&config.title_namespace, post.title.as_ref());
+    metadata.add_namespaced(&config.flair_namespace, post.link_flair_text.as_ref());
+    metadata.add_namespaced(&config.date_namespace, date);
+    if post.over_18 {
+        metadata.add_tag(&config.nsfw_tag);
+    }
+    if config.selftext_note && !post.selftext.trim().is_empty() {
+        metadata
+            .notes
+            .insert(SELFTEXT_NOTE.to_string(), post.selftext.trim().to_string());
+    }
+
+    metadata
+}
+
+/// Fails if the post has been removed and has no media left
+fn check_removed(url: &str, post: &T3Data) -> Result<()> {
+    if let Some(category) = &post.removed_by_category {
+        tracing::debug!("Post {} was removed ({category})", post.id);
+        return Err(RedditError::PostRemoved(url.to_owned()).into());
+    }
+
+    Ok(())
+}
+
+/// Returns the post from the listings returned for a post url
+fn post_from_response(url: &str, response: Vec<DataEntry>) -> Result<PostEntry> {
+    let unexpected = |message: &str| RedditError::UnexpectedResponse {
+        url: url.to_owned(),
+        message: message.to_owned(),
     };
-    first_listing.reverse();
-    let entry = first_listing.pop().unwrap();
+    let DataEntry::Listing(listing) = response
+        .into_iter()
+        .next()
+        .ok_or_else(|| unexpected("no listing returned"))?;
+    let entry = listing
+        .children
+        .into_iter()
+        .next()
+        .ok_or_else(|| unexpected("empty listing"))?;
 
     match entry {
-        DataEntryChild::T3(t3) => Ok(t3),
-        DataEntryChild::T1(_) | DataEntryChild::More(_) => panic!("Invalid data entry t1 or more"),
+        DataEntryChild::T3(t3) => Ok(PostEntry::Post(t3)),
+        DataEntryChild::T1(t1) => {
+            let post_id = t1
+                .link_id
+                .as_deref()
+                .map(|id| id.trim_start_matches("t3_").to_owned())
+                .ok_or_else(|| RedditError::CommentLink(url.to_owned()))?;
+            Ok(PostEntry::Comment(post_id))
+        }
+        DataEntryChild::More(_) => Err(unexpected("listing only contains more entries").into()),
     }
 }
 
+/// Returns the url of the post for comment permalinks
+/// like `/r/<sub>/comments/<post id>/<title>/<comment id>/`
+fn post_url_of_comment(url: &str) -> Option<String> {
+    let (_, post_url, _) = regex_captures!(
+        r#"^(https?://[^/]+/r/\w+/comments/\w+)/[^/?]*/\w+/?(\?.*)?$"#i,
+        url
+    )?;
+
+    Some(format!("{post_url}/"))
+}
+
 /// Resolves reddit redirects
-#[tracing::instrument(level = "debug")]
-async fn resolve_redirects(url: &str) -> Result<String> {
+#[tracing::instrument(level = "debug", skip(client))]
+async fn resolve_redirects(client: &RedditClient, url: &str) -> Result<String> {
     let mut url = url.to_string();
 
     for _ in 0..10 {
@@ -118,18 +734,15 @@ async fn resolve_redirects(url: &str) -> Result<String> {
             tracing::debug!("Url already resolved.");
             return Ok(url);
         }
-        let client = reqwest::Client::builder()
-            .user_agent(fakeit::user_agent::random_platform())
-            .redirect(Policy::none())
-            .build()?;
-        let response = client.get(url).send().await?;
-
-        if let Some(location) = response.headers().get("location") {
-            tracing::debug!("Redirect to {location:?} found");
-            url = location.to_str().unwrap().to_string();
-        } else {
-            tracing::debug!("No redirect found.");
-            return Ok(response.url().as_str().to_string());
+        match client.redirect_location(&url).await? {
+            Some(location) => {
+                tracing::debug!("Redirect to {location:?} found");
+                url = location;
+            }
+            None => {
+                tracing::debug!("No redirect found.");
+                return Ok(url);
+            }
         }
     }
 
@@ -145,18 +758,253 @@ fn is_resolved(url: &str) -> bool {
 
 #[cfg(test)]
 mod test {
+    use crate::config::RedditConfig;
+    use crate::utils::fixture;
+
+    fn handler() -> super::RedditHandler {
+        super::RedditHandler::new(RedditConfig::default()).unwrap()
+    }
+
+    /// Returns the post of the response stored in `fixtures/reddit/<name>.json`
+    fn fixture_post(name: &str) -> super::T3Data {
+        let response = fixture(&format!("reddit/{name}.json"));
+
+        match super::post_from_response("https://www.reddit.com/", response).unwrap() {
+            super::PostEntry::Post(post) => *post,
+            entry => panic!("Expected a post but got {entry:?}"),
+        }
+    }
+
+    #[test]
+    fn it_resolves_comments_to_their_post() {
+        let entry =
+            super::post_from_response("https://www.reddit.com/", fixture("reddit/comment.json"))
+                .unwrap();
+        assert!(matches!(entry, super::PostEntry::Comment(id) if id == "13v1d30"));
+
+        assert_eq!(
+            super::post_url_of_comment(
+                "https://www.reddit.com/r/aww/comments/13v1d30/video_post/jm2xyz9/?context=3"
+            )
+            .as_deref(),
+            Some("https://www.reddit.com/r/aww/comments/13v1d30/")
+        );
+        assert!(super::post_url_of_comment(
+            "https://www.reddit.com/r/aww/comments/13v1d30/video_post/"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn it_reports_unexpected_responses() {
+        use crate::error::{Error, RedditError};
+
+        let response = serde_json::from_str(r#"[{"kind": "Listing", "data": {"children": []}}]"#);
+        assert!(matches!(
+            super::post_from_response("https://www.reddit.com/", response.unwrap()),
+            Err(Error::Reddit(RedditError::UnexpectedResponse { .. }))
+        ));
+        assert!(super::post_from_response("https://www.reddit.com/", Vec::new()).is_err());
+    }
+
+    #[test]
+    fn it_detects_removed_posts() {
+        use crate::error::{Error, RedditError};
+
+        let url = "https://www.reddit.com/r/pics/comments/14r3m0v/removed_by_moderator/";
+        assert!(matches!(
+            super::check_removed(url, &fixture_post("removed_post")),
+            Err(Error::Reddit(RedditError::PostRemoved(removed))) if removed == url
+        ));
+        assert!(super::check_removed(url, &fixture_post("gallery_post")).is_ok());
+    }
+
+    #[test]
+    fn it_builds_gallery_urls_from_media_metadata() {
+        let post = fixture_post("gallery_post");
+
+        assert_eq!(
+            super::post_images(&RedditConfig::default(), post),
+            vec![
+                "https://i.redd.it/pngitem01.png",
+                "https://i.redd.it/gifitem02.gif",
+                "https://i.redd.it/jpgitem03.jpg",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_uses_the_fallback_url_of_videos() {
+        let post = fixture_post("video_post");
+
+        assert_eq!(
+            super::post_images(&RedditConfig::default(), post),
+            vec!["https://v.redd.it/vid0abc123/DASH_720.mp4?source=fallback"]
+        );
+    }
+
+    #[test]
+    fn it_imports_videos_with_audio_with_the_post_url() {
+        let post = fixture_post("video_post");
+        let config = RedditConfig {
+            video_post_url: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            super::post_images(&config, post),
+            vec!["https://www.reddit.com/r/aww/comments/13v1d30/video_post/"]
+        );
+    }
+
+    #[test]
+    fn it_uses_the_media_of_crossposted_posts() {
+        let post = fixture_post("crosspost");
+
+        assert_eq!(
+            super::post_images(&RedditConfig::default(), post),
+            vec!["https://v.redd.it/vid0abc123/DASH_720.mp4?source=fallback"]
+        );
+    }
+
+    #[test]
+    fn it_builds_listing_urls() {
+        use super::RedditListing;
+        use crate::args::{ListingSort, ListingTime};
+
+        let listing =
+            RedditListing::parse("https://www.reddit.com/r/pics/", ListingSort::Top, None).unwrap();
+        assert_eq!(
+            listing.page_url(Some("t3_abc"), None),
+            "https://www.reddit.com/r/pics/top.json?limit=100&after=t3_abc"
+        );
+        let listing =
+            RedditListing::parse("u/someone", ListingSort::New, Some(ListingTime::Week)).unwrap();
+        assert_eq!(
+            listing.page_url(None, Some(5)),
+            "https://www.reddit.com/user/someone/submitted.json?sort=new&limit=5&t=week"
+        );
+        let listing = RedditListing::parse("u/someone/m/art", ListingSort::Hot, None).unwrap();
+        assert_eq!(
+            listing.page_url(None, Some(500)),
+            "https://www.reddit.com/user/someone/m/art/hot.json?limit=100"
+        );
+        assert!(RedditListing::parse("/user/someone/saved", ListingSort::New, None).is_ok());
+        assert!(RedditListing::parse("https://example.com", ListingSort::New, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn it_requires_credentials_for_saved_posts() {
+        use super::{RedditHandler, RedditListing};
+        use crate::args::ListingSort;
+
+        let listing = RedditListing::parse("u/someone/saved", ListingSort::New, None).unwrap();
+        let handler = RedditHandler::new(RedditConfig {
+            refresh_token: Some("token".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        let error = handler.listing_page(&listing, None, None).await.err();
+
+        assert!(error.is_some_and(|e| e.to_string().contains("client_id")));
+    }
+
+    #[test]
+    fn it_recognizes_external_hosts() {
+        use super::ImgurUrl;
+
+        assert_eq!(
+            ImgurUrl::parse("https://imgur.com/a/AbC12"),
+            Some(ImgurUrl::Album("AbC12".into()))
+        );
+        assert_eq!(
+            ImgurUrl::parse("https://imgur.com/gallery/some-title-AbC12"),
+            Some(ImgurUrl::Album("AbC12".into()))
+        );
+        assert_eq!(
+            ImgurUrl::parse("https://imgur.com/XyZ34"),
+            Some(ImgurUrl::Image("XyZ34".into()))
+        );
+        assert_eq!(
+            super::imgur_gifv_to_mp4("https://i.imgur.com/XyZ34.gifv").as_deref(),
+            Some("https://i.imgur.com/XyZ34.mp4")
+        );
+        assert_eq!(
+            super::redgifs_id_from_url("https://www.redgifs.com/watch/QuietLoudHare").as_deref(),
+            Some("quietloudhare")
+        );
+        assert_eq!(
+            super::redgifs_id_from_url("https://gfycat.com/QuietLoudHare-loop").as_deref(),
+            Some("quietloudhare")
+        );
+        assert!(super::is_reddit_video_url("https://v.redd.it/vid0abc123"));
+    }
+
+    #[test]
+    fn it_prefers_gif_variants_of_animated_gallery_items() {
+        let post = fixture_post("animated_gallery_post");
+
+        assert_eq!(
+            super::post_images(&RedditConfig::default(), post),
+            vec![
+                "https://i.redd.it/gifitem01.gif",
+                "https://i.redd.it/webpitem02.webp",
+                "https://preview.redd.it/mp4item03.gif?format=mp4&s=d00d",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_creates_post_metadata() {
+        let post: super::T3Data = serde_json::from_value(serde_json::json!({
+            "id": "wmx2k3",
+            "url": "https://i.redd.it/abc.png",
+            "subreddit": "196",
+            "author": "someone",
+            "title": "dame da rule",
+            "link_flair_text": null,
+            "over_18": true,
+            "created_utc": 1660233600.0,
+            "selftext": "some text"
+        }))
+        .unwrap();
+        let config = RedditConfig {
+            date_namespace: "date".to_string(),
+            selftext_note: true,
+            ..Default::default()
+        };
+        let metadata = super::post_metadata(&config, &post);
+        let tags: Vec<String> = metadata.tags.iter().map(|t| t.to_string()).collect();
+
+        assert_eq!(
+            tags,
+            vec![
+                "subreddit:196",
+                "creator:someone",
+                "title:dame da rule",
+                "date:2022-08-11",
+                "meta:nsfw",
+            ]
+        );
+        assert_eq!(
+            metadata.notes.get(super::SELFTEXT_NOTE).map(String::as_str),
+            Some("some text")
+        );
+    }
+
     #[tokio::test]
     async fn it_finds_post_images() {
-        let images =
-            super::get_post_images("https://www.reddit.com/r/196/comments/wmx2k3/dame_da_rule/")
-                .await
-                .unwrap();
+        let images = handler()
+            .post_images("https://www.reddit.com/r/196/comments/wmx2k3/dame_da_rule/")
+            .await
+            .unwrap();
         assert!(images.is_empty() == false);
     }
 
     #[tokio::test]
     async fn it_finds_post_images2() {
-        let images = super::get_post_images("https://reddit.com/r/HentaiBullying/s/S1gKoG4s2S/")
+        let images = handler()
+            .post_images("https://reddit.com/r/HentaiBullying/s/S1gKoG4s2S/")
             .await
             .unwrap();
         assert!(images.is_empty() == false);
@@ -164,16 +1012,17 @@ mod test {
 
     #[tokio::test]
     async fn it_finds_multiple_post_images() {
-        let images =
-            super::get_post_images("https://www.reddit.com/r/dogelore/comments/wmas8c/le_yakuza/")
-                .await
-                .unwrap();
+        let images = handler()
+            .post_images("https://www.reddit.com/r/dogelore/comments/wmas8c/le_yakuza/")
+            .await
+            .unwrap();
         assert!(images.is_empty() == false);
     }
 
     #[tokio::test]
     async fn it_finds_info_for_posts() {
-        let post = super::get_post("https://www.reddit.com/r/196/comments/wmx2k3/dame_da_rule/")
+        let post = handler()
+            .get_post("https://www.reddit.com/r/196/comments/wmx2k3/dame_da_rule/")
             .await
             .unwrap();
         println!("{:?}", post.url);
@@ -181,7 +1030,8 @@ mod test {
     }
     #[tokio::test]
     async fn it_finds_info_for_gallery_posts() {
-        let post = super::get_post("https://www.reddit.com/r/dogelore/comments/wmas8c/le_yakuza/")
+        let post = handler()
+            .get_post("https://www.reddit.com/r/dogelore/comments/wmas8c/le_yakuza/")
             .await
             .unwrap();
         println!("{:?}", post.gallery_data);
@@ -0,0 +1,307 @@
+use std::time::Duration;
+
+use crate::config::RedditConfig;
+use crate::error::{RedditError, Result};
+use lazy_regex::regex;
+use reqwest::header::{HeaderMap, LOCATION};
+use reqwest::redirect::Policy;
+use reqwest::{Client, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+const TOKEN_URL: &str = "https://www.reddit.com/api/v1/access_token";
+const INSTALLED_CLIENT_GRANT: &str = "https://oauth.reddit.com/grants/installed_client";
+/// Tokens are renewed this long before they expire
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+/// The time waited after a 429 response without rate limit headers
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(60);
+const MAX_RETRIES: usize = 3;
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// The body of responses for private, quarantined or banned subreddits
+#[derive(Deserialize)]
+struct ErrorResponse {
+    reason: Option<String>,
+}
+
+struct AccessToken {
+    token: String,
+    expires_at: Instant,
+}
+
+#[derive(Clone, Debug)]
+struct Credentials {
+    client_id: String,
+    client_secret: Option<String>,
+    refresh_token: Option<String>,
+}
+
+/// The rate limit reported by reddit with the last response
+#[derive(Default, Debug)]
+struct RateLimit {
+    remaining: Option<f64>,
+    reset_at: Option<Instant>,
+}
+
+/// Sends requests to the reddit json api. Requests are authenticated with oauth
+/// when a client id is configured and wait for the rate limit to reset when it is used up
+pub struct RedditClient {
+    http: Client,
+    /// Client that doesn't follow redirects to resolve share links
+    redirect_http: Client,
+    credentials: Option<Credentials>,
+    token: Mutex<Option<AccessToken>>,
+    rate_limit: Mutex<RateLimit>,
+}
+
+impl RedditClient {
+    pub fn new(config: &RedditConfig) -> Result<Self> {
+        let credentials = config.client_id.as_ref().map(|client_id| Credentials {
+            client_id: client_id.to_owned(),
+            client_secret: config.client_secret.clone(),
+            refresh_token: config.refresh_token.clone(),
+        });
+        // reddit requires a descriptive user agent for oauth clients
+        let user_agent = match (&config.user_agent, &credentials) {
+            (Some(user_agent), _) => user_agent.to_owned(),
+            (None, Some(_)) => format!("hydrus-utils/{}", env!("CARGO_PKG_VERSION")),
+            (None, None) => fakeit::user_agent::random_platform(),
+        };
+        let http = Client::builder().user_agent(&user_agent).build()?;
+        let redirect_http = Client::builder()
+            .user_agent(user_agent)
+            .redirect(Policy::none())
+            .build()?;
+
+        Ok(Self {
+            http,
+            redirect_http,
+            credentials,
+            token: Mutex::new(None),
+            rate_limit: Mutex::new(RateLimit::default()),
+        })
+    }
+
+    /// Requests the json of the given reddit url. Urls are rewritten
+    /// to use the oauth api when the client is authenticated
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn get_json<T: DeserializeOwned>(&self, url: &str) -> Result<T> {
+        let url = if self.credentials.is_some() {
+            oauth_url(url)
+        } else {
+            url.to_owned()
+        };
+
+        let response = self
+            .send(&self.http, &url, self.credentials.is_some())
+            .await?;
+        let status = response.status();
+
+        if status == StatusCode::FORBIDDEN || status == StatusCode::NOT_FOUND {
+            let reason = response
+                .json::<ErrorResponse>()
+                .await
+                .ok()
+                .and_then(|r| r.reason);
+            return Err(access_error(url, status, reason).into());
+        }
+
+        Ok(response.error_for_status()?.json().await?)
+    }
+
+    /// Returns the location the url redirects to without following it.
+    /// Share links and `redd.it` urls are resolved with the public site
+    /// as the oauth api doesn't redirect them
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn redirect_location(&self, url: &str) -> Result<Option<String>> {
+        let response = self.send(&self.redirect_http, url, false).await?;
+        let Some(location) = response.headers().get(LOCATION) else {
+            return Ok(None);
+        };
+        let location = location
+            .to_str()
+            .map_err(|_| RedditError::UnexpectedResponse {
+                url: url.to_owned(),
+                message: "invalid redirect location".to_owned(),
+            })?;
+
+        Ok(Some(location.to_owned()))
+    }
+
+    /// Sends a get request and retries it when the rate limit was exceeded
+    async fn send(&self, http: &Client, url: &str, authenticate: bool) -> Result<Response> {
+        for _ in 0..MAX_RETRIES {
+            // the lock is held for the whole request so that requests
+            // don't exceed the rate limit when running concurrently
+            let mut rate_limit = self.rate_limit.lock().await;
+            rate_limit.wait().await;
+
+            let mut request = http.get(url);
+            if authenticate {
+                request = request.bearer_auth(self.access_token().await?);
+            }
+            let response = request.send().await?;
+            rate_limit.update(response.headers());
+
+            if response.status() == StatusCode::TOO_MANY_REQUESTS {
+                let delay = retry_delay(response.headers());
+                tracing::warn!("Reddit rate limit exceeded. Retrying in {:?}", delay);
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+
+            return Ok(response);
+        }
+
+        Err(format!("Reddit rate limit still exceeded after {MAX_RETRIES} retries").into())
+    }
+
+    /// Returns a valid access token and requests a new one if the current one expired
+    async fn access_token(&self) -> Result<String> {
+        let credentials = self
+            .credentials
+            .as_ref()
+            .ok_or("No reddit client id configured")?;
+        let mut token = self.token.lock().await;
+
+        if let Some(token) = token
+            .as_ref()
+            .filter(|t| t.expires_at > Instant::now() + TOKEN_EXPIRY_MARGIN)
+        {
+            return Ok(token.token.clone());
+        }
+        tracing::debug!("Requesting new reddit access token");
+        let params = match (&credentials.refresh_token, &credentials.client_secret) {
+            (Some(refresh_token), _) => vec![
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token.as_str()),
+            ],
+            (None, Some(_)) => vec![("grant_type", "client_credentials")],
+            (None, None) => vec![
+                ("grant_type", INSTALLED_CLIENT_GRANT),
+                ("device_id", "DO_NOT_TRACK_THIS_DEVICE"),
+            ],
+        };
+        let response: TokenResponse = self
+            .http
+            .post(TOKEN_URL)
+            .basic_auth(&credentials.client_id, credentials.client_secret.as_ref())
+            .form(&params)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let access_token = response.access_token.clone();
+        *token = Some(AccessToken {
+            token: response.access_token,
+            expires_at: Instant::now() + Duration::from_secs(response.expires_in),
+        });
+
+        Ok(access_token)
+    }
+}
+
+impl RateLimit {
+    /// Waits for the rate limit to reset if no requests are remaining
+    async fn wait(&self) {
+        if let (Some(remaining), Some(reset_at)) = (self.remaining, self.reset_at) {
+            if remaining < 1.0 && reset_at > Instant::now() {
+                tracing::info!(
+                    "Reddit rate limit used up. Waiting {:?} for it to reset",
+                    reset_at - Instant::now()
+                );
+                tokio::time::sleep_until(reset_at).await;
+            }
+        }
+    }
+
+    /// Updates the rate limit from the `X-Ratelimit-*` headers of a response
+    fn update(&mut self, headers: &HeaderMap) {
+        if let Some(remaining) = header_number(headers, "x-ratelimit-remaining") {
+            self.remaining = Some(remaining);
+        }
+        if let Some(reset) = header_number(headers, "x-ratelimit-reset") {
+            self.reset_at = Some(Instant::now() + Duration::from_secs_f64(reset));
+        }
+    }
+}
+
+fn access_error(url: String, status: StatusCode, reason: Option<String>) -> RedditError {
+    match (status, reason) {
+        (_, Some(reason)) => RedditError::Private { url, reason },
+        (StatusCode::FORBIDDEN, None) => RedditError::Private {
+            url,
+            reason: "private".to_string(),
+        },
+        (_, None) => RedditError::NotFound(url),
+    }
+}
+
+fn retry_delay(headers: &HeaderMap) -> Duration {
+    header_number(headers, "x-ratelimit-reset")
+        .or_else(|| header_number(headers, "retry-after"))
+        .map(Duration::from_secs_f64)
+        .unwrap_or(DEFAULT_RETRY_DELAY)
+}
+
+fn header_number(headers: &HeaderMap, name: &str) -> Option<f64> {
+    headers
+        .get(name)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite() && *n >= 0.0)
+}
+
+/// Rewrites urls of the public json api to the oauth api which doesn't use the `.json` suffix
+fn oauth_url(url: &str) -> String {
+    let url =
+        regex!(r#"^https?://(www\.|old\.)?reddit\.com"#i).replace(url, "https://oauth.reddit.com");
+
+    match url.split_once('?') {
+        Some((path, query)) => format!("{}?{query}", path.trim_end_matches(".json")),
+        None => url.trim_end_matches(".json").to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn it_rewrites_urls_to_the_oauth_api() {
+        assert_eq!(
+            oauth_url("https://www.reddit.com/r/pics/top.json?limit=100&t=week"),
+            "https://oauth.reddit.com/r/pics/top?limit=100&t=week"
+        );
+        assert_eq!(
+            oauth_url("https://www.reddit.com/r/pics/comments/abc/title/.json"),
+            "https://oauth.reddit.com/r/pics/comments/abc/title/"
+        );
+    }
+
+    #[test]
+    fn it_reads_rate_limit_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("0.0"));
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("42"));
+        let mut rate_limit = RateLimit::default();
+        rate_limit.update(&headers);
+
+        assert_eq!(rate_limit.remaining, Some(0.0));
+        assert!(rate_limit.reset_at.unwrap() > Instant::now() + Duration::from_secs(40));
+        assert_eq!(retry_delay(&headers), Duration::from_secs(42));
+        assert_eq!(retry_delay(&HeaderMap::new()), DEFAULT_RETRY_DELAY);
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use crate::config::RulesConfig;
+use crate::error::{Error, Result};
+use hydrus_api::wrapper::tag::Tag;
+use lazy_regex::Regex;
+
+/// Transforms tags according to the configured rules
+#[derive(Debug, Default)]
+pub struct TagRules {
+    lowercase: bool,
+    siblings: HashMap<String, String>,
+    rename: Vec<(Regex, String)>,
+    namespace: Vec<(Regex, String)>,
+    blacklist: Vec<Regex>,
+}
+
+impl TagRules {
+    pub fn new(config: RulesConfig) -> Result<Self> {
+        let rename = config
+            .rename
+            .into_iter()
+            .map(|r| Ok((compile(&r.pattern)?, r.replacement)))
+            .collect::<Result<_>>()?;
+        let namespace = config
+            .namespace
+            .into_iter()
+            .map(|r| Ok((compile(&r.pattern)?, r.namespace)))
+            .collect::<Result<_>>()?;
+        let blacklist = config
+            .blacklist
+            .iter()
+            .map(|p| compile(p))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            lowercase: config.lowercase,
+            siblings: config.siblings,
+            rename,
+            namespace,
+            blacklist,
+        })
+    }
+
+    /// Applies all rules to the tags and removes duplicates
+    pub fn apply(&self, tags: Vec<Tag>) -> Vec<Tag> {
+        let mut result: Vec<Tag> = Vec::with_capacity(tags.len());
+
+        for tag in tags {
+            if let Some(tag) = self.apply_to_tag(tag) {
+                if !result.contains(&tag) {
+                    result.push(tag);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Applies the rules to a single tag. Returns `None` if the tag is blacklisted
+    fn apply_to_tag(&self, tag: Tag) -> Option<Tag> {
+        let mut value = tag.to_string();
+
+        if self.lowercase {
+            value = value.to_lowercase();
+        }
+        if let Some(sibling) = self.siblings.get(&value) {
+            value = sibling.to_owned();
+        }
+        for (pattern, replacement) in &self.rename {
+            value = pattern
+                .replace_all(&value, replacement.as_str())
+                .into_owned();
+        }
+        let mut tag = Tag::from(value);
+
+        if tag.namespace.is_none() {
+            if let Some((_, namespace)) = self.namespace.iter().find(|(p, _)| p.is_match(&tag.name))
+            {
+                tag.namespace = Some(namespace.to_owned());
+            }
+        }
+        let value = tag.to_string();
+
+        if tag.name.is_empty() || self.blacklist.iter().any(|p| p.is_match(&value)) {
+            tracing::debug!("Dropping tag '{value}'");
+            None
+        } else {
+            Some(tag)
+        }
+    }
+}
+
+fn compile(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern)
+        .map_err(|e| Error::from(format!("Invalid tag rule pattern '{pattern}': {e}")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{NamespaceRule, RenameRule};
+
+    fn to_strings(tags: Vec<Tag>) -> Vec<String> {
+        tags.iter().map(Tag::to_string).collect()
+    }
+
+    #[test]
+    fn it_applies_all_rules() {
+        let rules = TagRules::new(RulesConfig {
+            lowercase: true,
+            siblings: [("blonde".to_owned(), "blonde hair".to_owned())].into(),
+            rename: vec![RenameRule {
+                pattern: r"^(.+) \(cosplay\)$".to_owned(),
+                replacement: "$1".to_owned(),
+            }],
+            namespace: vec![NamespaceRule {
+                pattern: r"\(genshin impact\)$".to_owned(),
+                namespace: "character".to_owned(),
+            }],
+            blacklist: vec!["^オリジナル$".to_owned(), "users入り$".to_owned()],
+        })
+        .unwrap();
+        let tags = [
+            "Blonde",
+            "オリジナル",
+            "1000users入り",
+            "Lumine (cosplay)",
+            "Lumine (Genshin Impact)",
+            "creator:Someone",
+        ]
+        .into_iter()
+        .map(Tag::from)
+        .collect();
+
+        assert_eq!(
+            to_strings(rules.apply(tags)),
+            vec![
+                "blonde hair",
+                "lumine",
+                "character:lumine (genshin impact)",
+                "creator:someone"
+            ]
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_patterns() {
+        let config = RulesConfig {
+            blacklist: vec!["(".to_owned()],
+            ..Default::default()
+        };
+
+        assert!(TagRules::new(config).is_err());
+    }
+}
@@ -0,0 +1,350 @@
+use crate::args::MatchOptions;
+use crate::config::{HydrusConfig, SauceNaoConfig, UploadMode};
+use crate::error::{Error, Result};
+use crate::utils::cache::LookupCache;
+use crate::utils::matching::select_matches;
+use crate::utils::thumbnails::ThumbnailClient;
+use clap::ValueEnum;
+use hydrus_api::wrapper::hydrus_file::HydrusFile;
+use rustnao::{ErrType, Handler, HandlerBuilder, Sauce};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tempdir::TempDir;
+use tokio::time::Instant;
+
+/// The saucenao indexes that can be selected in the config
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SauceIndex {
+    Pixiv,
+    Danbooru,
+    Gelbooru,
+    #[serde(rename = "yandere")]
+    #[value(name = "yandere")]
+    YandeRe,
+    Konachan,
+    E621,
+    Sankaku,
+    AnimePictures,
+    Nijie,
+    NicoNicoSeiga,
+    #[serde(rename = "deviantart")]
+    #[value(name = "deviantart")]
+    DeviantArt,
+    Pawoo,
+}
+
+impl SauceIndex {
+    /// Returns the index for the given saucenao index id
+    pub fn from_id(id: u32) -> Option<Self> {
+        let index = match id {
+            // 6 is the index for historical pixiv images
+            5 | 6 => Self::Pixiv,
+            Handler::DANBOORU => Self::Danbooru,
+            Handler::GELBOORU => Self::Gelbooru,
+            Handler::YANDE_RE => Self::YandeRe,
+            Handler::KONACHAN => Self::Konachan,
+            Handler::E621_NET => Self::E621,
+            Handler::SANKAKU_CHANNEL => Self::Sankaku,
+            Handler::ANIME_PICTURES_NET => Self::AnimePictures,
+            Handler::NIJIE => Self::Nijie,
+            Handler::NICO_NICO_SEIGA => Self::NicoNicoSeiga,
+            Handler::DEVIANTART => Self::DeviantArt,
+            Handler::PAWOO_NET => Self::Pawoo,
+            _ => return None,
+        };
+
+        Some(index)
+    }
+
+    /// Returns the index the sauce was found in
+    pub fn of_sauce(sauce: &Sauce) -> Option<Self> {
+        Self::from_id(sauce.index)
+    }
+}
+
+/// The minimum similarity for a sauce to be reported as a low similarity match
+const LOW_SIMILARITY: f32 = 60.0;
+/// The time window of the short saucenao search limit
+const SHORT_LIMIT_WINDOW: Duration = Duration::from_secs(30);
+/// The number of times a lookup is retried when the short limit was exceeded
+const MAX_RATE_LIMIT_RETRIES: usize = 3;
+const BYTES_PER_MB: u64 = 1024 * 1024;
+/// Image types that can be uploaded to saucenao as they are.
+/// All other files are looked up by their thumbnail
+const STATIC_IMAGE_TYPES: &[&str] = &["image/jpeg", "image/png", "image/webp", "image/bmp"];
+
+/// The result of looking up a file
+pub enum SauceMatch {
+    /// Sauces that have a high enough similarity to be used
+    Found(Vec<Sauce>),
+    /// Only sauces with a low similarity have been found
+    LowSimilarity,
+    /// The best sauces point to different sources
+    Ambiguous,
+    None,
+}
+
+/// Looks up hydrus files on saucenao
+pub struct SauceLookup {
+    config: SauceNaoConfig,
+    matching: MatchOptions,
+    handler: Handler,
+    thumbnails: ThumbnailClient,
+    cache: LookupCache,
+    tmpdir: TempDir,
+    limiter: RateLimiter,
+}
+
+impl SauceLookup {
+    pub fn new(
+        config: SauceNaoConfig,
+        hydrus: &HydrusConfig,
+        matching: MatchOptions,
+        cache: LookupCache,
+    ) -> Result<Self> {
+        let min_similarity = LOW_SIMILARITY.min(matching.lowest_min_similarity());
+        let handler = HandlerBuilder::new()
+            .api_key(&config.api_key)
+            .min_similarity(min_similarity)
+            .db(999)
+            .build();
+        // cached results don't contain sauces below the similarity they were searched with
+        let cache = cache.with_sauce_settings(&min_similarity)?;
+        let tmpdir = TempDir::new("hydrus-files")?;
+
+        Ok(Self {
+            config,
+            matching,
+            handler,
+            thumbnails: ThumbnailClient::new(hydrus),
+            cache,
+            tmpdir,
+            limiter: RateLimiter::default(),
+        })
+    }
+
+    pub fn cache(&self) -> &LookupCache {
+        &self.cache
+    }
+
+    /// Looks up the file and returns the sauces selected by the configured match strategy
+    pub async fn find_matches(&self, file: &mut HydrusFile) -> Result<SauceMatch> {
+        let sauces = self.get_sauces_for_file(file).await?;
+
+        Ok(select_matches(&self.matching, sauces))
+    }
+
+    /// Returns the sauces for the given file.
+    /// Results are taken from the cache if the file has been looked up before
+    async fn get_sauces_for_file(&self, file: &mut HydrusFile) -> Result<Vec<Sauce>> {
+        let hash = file.hash().await?;
+
+        let sauces = if let Some(sauces) = self.cache.sauces(&hash) {
+            tracing::debug!("Using cached sauces for hydrus file {:?}", file.id);
+            sauces
+        } else {
+            tracing::debug!("Creating tmp file for hydrus file {:?}", file.id);
+            let path = self.create_tmp_sauce_file(&hash, file).await?;
+            tracing::debug!("Getting sauce for hydrus file {:?}", file.id);
+
+            let sauces = self.get_sauce(&path).await;
+            fs::remove_file(path)?;
+            let sauces = sauces?;
+            self.cache.store_sauces(&hash, &sauces)?;
+            sauces
+        };
+        let sauces = filter_sauces(&self.config, sauces);
+        tracing::debug!(
+            "Found {} sauces for hydrus file {:?}",
+            sauces.len(),
+            file.id
+        );
+
+        Ok(sauces)
+    }
+
+    /// Writes the file or its thumbnail to the tmp dir so that it can be uploaded.
+    /// Videos and animations are always uploaded as thumbnails
+    async fn create_tmp_sauce_file(&self, hash: &str, file: &mut HydrusFile) -> Result<PathBuf> {
+        let mime = file.mime().await?;
+        let is_static_image = STATIC_IMAGE_TYPES.contains(&mime.essence_str());
+        let use_thumbnail = !is_static_image
+            || match self.config.upload {
+                UploadMode::File => false,
+                UploadMode::Thumbnail => true,
+                UploadMode::Auto => file
+                    .size()
+                    .await?
+                    .is_some_and(|size| size > self.config.max_upload_mb * BYTES_PER_MB),
+            };
+        let bytes = if use_thumbnail {
+            tracing::debug!("Using thumbnail of {mime} hydrus file {:?}", file.id);
+            self.thumbnails.thumbnail(hash).await?
+        } else {
+            file.retrieve().await?.bytes
+        };
+        let path = self.tmpdir.path().join(hash);
+        fs::write(&path, bytes)?;
+
+        Ok(path)
+    }
+
+    /// Sends the file to saucenao respecting the remaining search limits
+    async fn get_sauce(&self, path: &Path) -> Result<Vec<Sauce>> {
+        let mut retries = 0;
+
+        loop {
+            self.limiter.wait().await?;
+
+            match self.handler.get_sauce(path.to_str().unwrap(), None, None) {
+                Ok(sauces) => {
+                    self.limiter.update(SearchLimits::of_handler(&self.handler));
+                    return Ok(sauces);
+                }
+                Err(e) => match e.kind() {
+                    ErrType::InvalidCode { code: -2, .. } if retries < MAX_RATE_LIMIT_RETRIES => {
+                        tracing::warn!("Search limit exceeded. Retrying later...");
+                        self.limiter.back_off();
+                        retries += 1;
+                    }
+                    // the short limit resets within its window so a limit that
+                    // is still exceeded after waiting is the daily limit
+                    ErrType::InvalidCode { code: -2, .. } => return Err(Error::DailyLimitReached),
+                    _ => return Err(e.into()),
+                },
+            }
+        }
+    }
+}
+
+/// The search limits reported by saucenao with the last response
+struct SearchLimits {
+    short_limit: u32,
+    short_remaining: u32,
+    long_remaining: u32,
+}
+
+impl SearchLimits {
+    fn of_handler(handler: &Handler) -> Self {
+        Self {
+            short_limit: handler.get_short_limit(),
+            short_remaining: handler.get_current_short_limit(),
+            long_remaining: handler.get_current_long_limit(),
+        }
+    }
+}
+
+/// Spreads saucenao requests over the short limit window based on
+/// the remaining searches reported with each response
+#[derive(Default)]
+struct RateLimiter {
+    next_request: Cell<Option<Instant>>,
+    long_remaining: Cell<Option<u32>>,
+}
+
+impl RateLimiter {
+    /// Waits until the next request can be sent.
+    /// Fails if the previous request used up the daily limit
+    async fn wait(&self) -> Result<()> {
+        if self.long_remaining.get() == Some(0) {
+            return Err(Error::DailyLimitReached);
+        }
+        if let Some(next_request) = self.next_request.get() {
+            tokio::time::sleep_until(next_request).await;
+        }
+
+        Ok(())
+    }
+
+    /// Updates the limiter with the remaining searches reported by saucenao
+    fn update(&self, limits: SearchLimits) {
+        tracing::debug!(
+            "Remaining searches: {} short, {} long",
+            limits.short_remaining,
+            limits.long_remaining
+        );
+        self.long_remaining.set(Some(limits.long_remaining));
+
+        if limits.short_remaining == 0 {
+            self.back_off();
+        } else {
+            let interval = SHORT_LIMIT_WINDOW / limits.short_limit.max(1);
+            self.next_request.set(Some(Instant::now() + interval));
+        }
+    }
+
+    /// Pauses requests until the short limit window has passed
+    fn back_off(&self) {
+        tracing::info!(
+            "Short search limit reached. Waiting {} seconds",
+            SHORT_LIMIT_WINDOW.as_secs()
+        );
+        self.next_request
+            .set(Some(Instant::now() + SHORT_LIMIT_WINDOW));
+    }
+}
+
+/// Removes all sauces that weren't found in one of the configured indexes.
+/// The indexes are filtered here instead of using a db mask because every
+/// lookup costs the same amount of quota
+fn filter_sauces(config: &SauceNaoConfig, sauces: Vec<Sauce>) -> Vec<Sauce> {
+    if let Some(databases) = &config.databases {
+        sauces
+            .into_iter()
+            .filter(|s| SauceIndex::of_sauce(s).is_some_and(|i| databases.contains(&i)))
+            .collect()
+    } else {
+        sauces
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits(short_remaining: u32, long_remaining: u32) -> SearchLimits {
+        SearchLimits {
+            short_limit: 6,
+            short_remaining,
+            long_remaining,
+        }
+    }
+
+    fn delay(limiter: &RateLimiter) -> Duration {
+        limiter.next_request.get().unwrap() - Instant::now()
+    }
+
+    #[test]
+    fn it_spreads_requests_over_the_short_window() {
+        let limiter = RateLimiter::default();
+        limiter.update(limits(4, 100));
+
+        let delay = delay(&limiter);
+        assert!(delay <= SHORT_LIMIT_WINDOW / 6);
+        assert!(delay > SHORT_LIMIT_WINDOW / 6 - Duration::from_secs(1));
+    }
+
+    #[test]
+    fn it_backs_off_when_the_short_limit_is_reached() {
+        let limiter = RateLimiter::default();
+        limiter.update(limits(0, 100));
+
+        assert!(delay(&limiter) > SHORT_LIMIT_WINDOW - Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn it_stops_when_the_daily_limit_is_reached() {
+        let limiter = RateLimiter::default();
+        assert!(limiter.wait().await.is_ok());
+
+        limiter.update(limits(4, 0));
+        assert!(matches!(
+            limiter.wait().await,
+            Err(Error::DailyLimitReached)
+        ));
+    }
+}
@@ -0,0 +1,194 @@
+use crate::args::{SearchOptions, SortBy};
+use crate::error::{Error, Result};
+use hydrus_api::wrapper::builders::search_builder::{SearchBuilder, SortType};
+use hydrus_api::wrapper::builders::tag_builder::{Comparator, FileSizeUnit, SystemTagBuilder};
+use hydrus_api::wrapper::service::ServiceName;
+use hydrus_api::wrapper::tag::Tag;
+use hydrus_api::Hydrus;
+
+/// Creates a file search with the given tags and system predicates
+pub fn build_search(hydrus: &Hydrus, tags: Vec<Tag>, opt: &SearchOptions) -> Result<SearchBuilder> {
+    let mut search = hydrus.search().add_tags(tags);
+
+    for tag in system_tags(opt)? {
+        search = search.add_tag(tag);
+    }
+    if let Some(sort) = opt.sort {
+        search = search.sort_by(sort_type(sort));
+
+        search = if opt.ascending == Some(true) {
+            search.sort_ascending()
+        } else {
+            search.sort_descending()
+        };
+    }
+    if let Some(file_service) = &opt.file_service {
+        search = search.file_service_name(ServiceName(file_service.to_owned()));
+    }
+
+    Ok(search)
+}
+
+/// Converts the search options into system predicate tags
+fn system_tags(opt: &SearchOptions) -> Result<Vec<Tag>> {
+    let mut tags = Vec::new();
+
+    if opt.inbox == Some(true) {
+        tags.push(SystemTagBuilder::new().inbox().build());
+    }
+    if opt.archive == Some(true) {
+        tags.push(SystemTagBuilder::new().archive().build());
+    }
+    if !opt.filetype.is_empty() {
+        // filetypes are passed as is to also support groups like `image` or `video`
+        tags.push(Tag::from(format!(
+            "system:filetype = {}",
+            opt.filetype.join(", ")
+        )));
+    }
+    if let Some(size) = &opt.min_size {
+        let (value, unit) = parse_file_size(size)?;
+        tags.push(
+            SystemTagBuilder::new()
+                .filesize(Comparator::Greater, value, unit)
+                .build(),
+        );
+    }
+    if let Some(size) = &opt.max_size {
+        let (value, unit) = parse_file_size(size)?;
+        tags.push(
+            SystemTagBuilder::new()
+                .filesize(Comparator::Less, value, unit)
+                .build(),
+        );
+    }
+    if let Some(age) = &opt.imported_within {
+        tags.push(Tag::from(format!(
+            "system:time imported < {}",
+            parse_age(age)?
+        )));
+    }
+    if let Some(limit) = opt.limit {
+        tags.push(SystemTagBuilder::new().limit(limit).build());
+    }
+
+    Ok(tags)
+}
+
+fn sort_type(sort: SortBy) -> SortType {
+    match sort {
+        SortBy::FileSize => SortType::FileSize,
+        SortBy::Duration => SortType::Duration,
+        SortBy::ImportTime => SortType::ImportTime,
+        SortBy::FileType => SortType::FileType,
+        SortBy::Random => SortType::Random,
+        SortBy::Width => SortType::Width,
+        SortBy::Height => SortType::Height,
+        SortBy::NumberOfTags => SortType::NumberOfTags,
+        SortBy::ModifiedTime => SortType::ModifiedTime,
+    }
+}
+
+/// Parses file sizes like `500KB` or `10 MB`
+fn parse_file_size(size: &str) -> Result<(u64, FileSizeUnit)> {
+    let (value, unit) = split_number(size)?;
+    let unit = match unit.to_lowercase().as_str() {
+        "" | "b" => FileSizeUnit::Bytes,
+        "k" | "kb" => FileSizeUnit::Kilobytes,
+        "m" | "mb" => FileSizeUnit::Megabytes,
+        "g" | "gb" => FileSizeUnit::Gigabytes,
+        _ => return Err(Error::from(format!("Invalid file size unit in '{size}'"))),
+    };
+
+    Ok((value, unit))
+}
+
+/// Parses durations like `12h` or `7d` into the format used by hydrus
+fn parse_age(age: &str) -> Result<String> {
+    let (value, unit) = split_number(age)?;
+    let (value, unit) = match unit.to_lowercase().as_str() {
+        "m" | "min" | "minutes" => (value, "minutes"),
+        "h" | "hours" => (value, "hours"),
+        "" | "d" | "days" => (value, "days"),
+        "w" | "weeks" => (value * 7, "days"),
+        _ => return Err(Error::from(format!("Invalid duration unit in '{age}'"))),
+    };
+
+    Ok(format!("{value} {unit}"))
+}
+
+/// Splits a value like `10MB` into the number and the unit
+fn split_number(value: &str) -> Result<(u64, &str)> {
+    let value = value.trim();
+    let unit_start = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(unit_start);
+    let number = number
+        .parse()
+        .map_err(|_| Error::from(format!("Invalid number in '{value}'")))?;
+
+    Ok((number, unit.trim()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_builds_system_predicates() {
+        let opt = SearchOptions {
+            inbox: Some(true),
+            filetype: vec!["image/png".into(), "image/jpeg".into()],
+            max_size: Some("10MB".into()),
+            imported_within: Some("1d".into()),
+            limit: Some(500),
+            ..Default::default()
+        };
+        let tags: Vec<String> = system_tags(&opt)
+            .unwrap()
+            .iter()
+            .map(|t| t.to_string())
+            .collect();
+
+        assert_eq!(
+            tags,
+            vec![
+                "system:inbox",
+                "system:filetype = image/png, image/jpeg",
+                "system:filesize < 10 MB",
+                "system:time imported < 1 days",
+                "system:limit = 500",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_overrides_config_defaults() {
+        use clap::Parser;
+
+        #[derive(Parser)]
+        struct Cli {
+            #[clap(flatten)]
+            search: SearchOptions,
+        }
+        let defaults = SearchOptions {
+            inbox: Some(true),
+            ascending: Some(true),
+            ..Default::default()
+        };
+        let cli = Cli::parse_from(["hydrus-utils", "--inbox=false", "--archive"]);
+        let opt = cli.search.or(defaults);
+
+        assert_eq!(opt.inbox, Some(false));
+        assert_eq!(opt.archive, Some(true));
+        assert_eq!(opt.ascending, Some(true));
+    }
+
+    #[test]
+    fn it_rejects_invalid_units() {
+        assert!(parse_file_size("10 parsecs").is_err());
+        assert!(parse_age("3 fortnights").is_err());
+        assert!(parse_age("d").is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+
+use crate::args::PageOptions;
+use crate::config::Config;
+use crate::error::Result;
+use crate::utils::changes::FileChanges;
+use crate::utils::fedi::FediHandler;
+use crate::utils::pixiv::{PixivHandler, PixivTagger};
+use crate::utils::reddit::RedditHandler;
+use crate::utils::twitter::TwitterHandler;
+use futures::future::BoxFuture;
+use hydrus_api::wrapper::tag::Tag;
+
+/// A file found for a url and the metadata it is imported with
+#[derive(Clone, Debug, Default)]
+pub struct ResolvedMedia {
+    /// The url the file is imported from
+    pub url: String,
+    /// Urls associated with the imported file
+    pub urls: Vec<String>,
+    pub tags: Vec<Tag>,
+    /// Notes mapped by their name
+    pub notes: BTreeMap<String, String>,
+    /// Referer the file has to be requested with. Files with a referer
+    /// are downloaded and sent to hydrus instead of importing their url
+    pub referer: Option<String>,
+}
+
+impl ResolvedMedia {
+    /// Creates media imported from `url` that is associated with `source_url`
+    pub fn new<S1: ToString, S2: ToString>(url: S1, source_url: S2) -> Self {
+        Self {
+            url: url.to_string(),
+            urls: vec![source_url.to_string()],
+            ..Default::default()
+        }
+    }
+
+    /// Converts the metadata into changes for the given tag service
+    pub fn into_changes(self, service_key: &str) -> FileChanges {
+        let mut changes = FileChanges {
+            urls: self.urls,
+            notes: self.notes,
+            ..Default::default()
+        };
+        changes.add_tags(service_key, self.tags);
+
+        changes
+    }
+}
+
+/// Resolves urls of a site to the media that should be imported
+pub trait SourceHandler: Send + Sync {
+    /// The name of the site used in logs
+    fn name(&self) -> &'static str;
+
+    /// Returns if the handler can resolve the url
+    fn matches<'a>(&'a self, url: &'a str) -> BoxFuture<'a, bool>;
+
+    /// Returns all media that should be imported for the url
+    fn resolve<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Vec<ResolvedMedia>>>;
+}
+
+/// Selects the handler for a url. Handlers are matched in the order they were added
+#[derive(Default)]
+pub struct SourceRegistry {
+    handlers: Vec<Box<dyn SourceHandler>>,
+}
+
+impl SourceRegistry {
+    /// Creates a registry with handlers for all supported sites
+    pub fn from_config(config: &Config, pages: PageOptions) -> Result<Self> {
+        // fedi urls are matched last as they can only be detected with a request
+        let registry = Self::default()
+            .with(RedditHandler::new(config.reddit.clone())?)
+            .with(PixivHandler::new(
+                PixivTagger::new(config.pixiv.clone()),
+                pages,
+            ))
+            .with(TwitterHandler::new(config.twitter.clone()))
+            .with(FediHandler::new(config.fedi.clone()));
+
+        Ok(registry)
+    }
+
+    pub fn with<H: SourceHandler + 'static>(mut self, handler: H) -> Self {
+        self.handlers.push(Box::new(handler));
+
+        self
+    }
+
+    /// Returns the first handler that matches the url
+    pub async fn find_handler(&self, url: &str) -> Option<&dyn SourceHandler> {
+        for handler in &self.handlers {
+            if handler.matches(url).await {
+                return Some(handler.as_ref());
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+
+    struct StubHandler {
+        name: &'static str,
+        prefix: &'static str,
+    }
+
+    impl SourceHandler for StubHandler {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn matches<'a>(&'a self, url: &'a str) -> BoxFuture<'a, bool> {
+            futures::future::ready(url.starts_with(self.prefix)).boxed()
+        }
+
+        fn resolve<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Vec<ResolvedMedia>>> {
+            futures::future::ready(Ok(vec![ResolvedMedia::new(url, url)])).boxed()
+        }
+    }
+
+    fn stub(name: &'static str, prefix: &'static str) -> StubHandler {
+        StubHandler { name, prefix }
+    }
+
+    #[test]
+    fn it_finds_the_first_matching_handler() {
+        let registry = SourceRegistry::default()
+            .with(stub("pixiv", "https://www.pixiv.net/"))
+            .with(stub("fallback", "https://"));
+
+        let find = |url| registry.find_handler(url).now_or_never().flatten();
+        assert_eq!(
+            find("https://www.pixiv.net/artworks/1").map(|h| h.name()),
+            Some("pixiv")
+        );
+        assert_eq!(
+            find("https://example.com/1.png").map(|h| h.name()),
+            Some("fallback")
+        );
+        assert!(find("ftp://example.com/1.png").is_none());
+    }
+}
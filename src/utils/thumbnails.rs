@@ -0,0 +1,37 @@
+use crate::config::HydrusConfig;
+use crate::error::Result;
+use reqwest::Client;
+
+/// Retrieves file thumbnails from the hydrus client api
+/// as the hydrus api wrapper doesn't expose them
+pub struct ThumbnailClient {
+    client: Client,
+    api_url: String,
+    api_key: String,
+}
+
+impl ThumbnailClient {
+    pub fn new(config: &HydrusConfig) -> Self {
+        Self {
+            client: Client::new(),
+            api_url: config.api_url.trim_end_matches('/').to_owned(),
+            api_key: config.api_key.clone(),
+        }
+    }
+
+    /// Returns the thumbnail of the file with the given hash
+    pub async fn thumbnail(&self, hash: &str) -> Result<Vec<u8>> {
+        let bytes = self
+            .client
+            .get(format!("{}/get_files/thumbnail", self.api_url))
+            .query(&[("hash", hash)])
+            .header("Hydrus-Client-API-Access-Key", &self.api_key)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        Ok(bytes.to_vec())
+    }
+}
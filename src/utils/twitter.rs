@@ -0,0 +1,187 @@
+use crate::config::TwitterConfig;
+use crate::error::Result;
+use crate::utils::sources::{ResolvedMedia, SourceHandler};
+use egg_mode::auth::{bearer_token, KeyPair, Token};
+use egg_mode::entities::{MediaEntity, MediaType};
+use egg_mode::tweet::Tweet;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hydrus_api::wrapper::builders::tag_builder::TagBuilder;
+use hydrus_api::wrapper::tag::Tag;
+use lazy_regex::regex_captures;
+use tokio::sync::OnceCell;
+
+/// The media and tags of a tweet
+#[derive(Debug, Default)]
+pub struct TweetInfo {
+    pub media_urls: Vec<String>,
+    pub tags: Vec<Tag>,
+}
+
+/// Retrieves tweets with an app-only bearer token
+pub struct TwitterClient {
+    token: Token,
+}
+
+impl TwitterClient {
+    pub async fn new(config: &TwitterConfig) -> Result<Self> {
+        let key_pair = KeyPair::new(config.consumer_key.clone(), config.consumer_secret.clone());
+        let token = bearer_token(&key_pair).await?;
+
+        Ok(Self { token })
+    }
+
+    /// Returns the full resolution media and the tags of the tweet with the given id
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn tweet_info(&self, id: u64) -> Result<TweetInfo> {
+        let tweet = egg_mode::tweet::show(id, &self.token).await?.response;
+
+        Ok(tweet_info(tweet))
+    }
+}
+
+/// Imports the media of tweets. The client is only created
+/// once the first tweet is resolved
+pub struct TwitterHandler {
+    config: Option<TwitterConfig>,
+    client: OnceCell<TwitterClient>,
+}
+
+impl TwitterHandler {
+    pub fn new(config: Option<TwitterConfig>) -> Self {
+        Self {
+            config,
+            client: OnceCell::new(),
+        }
+    }
+
+    async fn client(&self) -> Result<&TwitterClient> {
+        let config = self
+            .config
+            .as_ref()
+            .ok_or("No twitter credentials configured. Please add them to the config file.")?;
+
+        self.client
+            .get_or_try_init(|| TwitterClient::new(config))
+            .await
+    }
+}
+
+impl SourceHandler for TwitterHandler {
+    fn name(&self) -> &'static str {
+        "twitter"
+    }
+
+    fn matches<'a>(&'a self, url: &'a str) -> BoxFuture<'a, bool> {
+        futures::future::ready(tweet_id_from_url(url).is_some()).boxed()
+    }
+
+    fn resolve<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Vec<ResolvedMedia>>> {
+        async move {
+            let id = tweet_id_from_url(url).ok_or("Invalid tweet url")?;
+            let info = self.client().await?.tweet_info(id).await?;
+
+            let media = info
+                .media_urls
+                .into_iter()
+                .map(|media_url| {
+                    let mut media = ResolvedMedia::new(media_url, url);
+                    media.tags = info.tags.clone();
+                    media
+                })
+                .collect();
+
+            Ok(media)
+        }
+        .boxed()
+    }
+}
+
+fn tweet_info(tweet: Tweet) -> TweetInfo {
+    let media = tweet
+        .extended_entities
+        .map(|e| e.media)
+        .or(tweet.entities.media)
+        .unwrap_or_default();
+    let mut tags: Vec<Tag> = tweet
+        .entities
+        .hashtags
+        .into_iter()
+        .map(|h| TagBuilder::new(h.text.to_lowercase()).build())
+        .collect();
+
+    if let Some(user) = tweet.user {
+        tags.push(
+            TagBuilder::new(user.screen_name)
+                .namespace("creator")
+                .build(),
+        );
+    }
+
+    TweetInfo {
+        media_urls: media.iter().filter_map(media_url).collect(),
+        tags,
+    }
+}
+
+/// Returns the url of the media in its original resolution or
+/// the variant with the highest bitrate for videos
+fn media_url(media: &MediaEntity) -> Option<String> {
+    match media.media_type {
+        MediaType::Photo => Some(format!("{}?name=orig", media.media_url_https)),
+        MediaType::Video | MediaType::Gif => media
+            .video_info
+            .as_ref()?
+            .variants
+            .iter()
+            .filter(|v| v.content_type.essence_str() == "video/mp4")
+            .max_by_key(|v| v.bitrate.unwrap_or_default())
+            .map(|v| v.url.clone()),
+    }
+}
+
+/// Extracts the tweet id from twitter and x status urls
+pub fn tweet_id_from_url(url: &str) -> Option<u64> {
+    let (_, id) = regex_captures!(
+        r#"^https?://(?:www\.|mobile\.)?(?:twitter|x)\.com/[^/]+/status(?:es)?/(\d+)"#i,
+        url
+    )?;
+
+    id.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::fixture;
+
+    #[test]
+    fn it_extracts_tweet_ids() {
+        assert_eq!(
+            tweet_id_from_url("https://twitter.com/someone/status/1234567890?s=20"),
+            Some(1234567890)
+        );
+        assert_eq!(
+            tweet_id_from_url("https://x.com/someone/status/42/photo/1"),
+            Some(42)
+        );
+        assert_eq!(tweet_id_from_url("https://twitter.com/someone"), None);
+    }
+
+    #[test]
+    fn it_maps_tweets_to_media_and_tags() {
+        let tweet: Tweet = fixture("twitter/tweet.json");
+        let info = tweet_info(tweet);
+        let tags: Vec<String> = info.tags.iter().map(Tag::to_string).collect();
+
+        assert_eq!(tags, vec!["artwork", "sketch", "creator:SomeArtist"]);
+        assert_eq!(
+            info.media_urls,
+            vec![
+                "https://pbs.twimg.com/media/F0abcPhoto.jpg?name=orig",
+                "https://video.twimg.com/ext_tw_video/1675123456700000002/pu/vid/1280x720/high.mp4",
+                "https://video.twimg.com/tweet_video/F0abcGif.mp4",
+            ]
+        );
+    }
+}